@@ -0,0 +1,222 @@
+use pi::sd::{Sd, SECTOR_SIZE};
+use shim::io;
+use shim::ioerr;
+
+/// Partition types of FAT16 and FAT32 partitions in the MBR.
+const FAT_PARTITION_TYPES: [u8; 5] = [0x04, 0x06, 0x0b, 0x0c, 0x0e];
+
+// Directory entry attributes.
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// Where the root directory lives.
+enum Root {
+    /// FAT16: a fixed run of sectors before the data region.
+    Sectors { start: u32, count: u32 },
+    /// FAT32: a cluster chain like any other directory.
+    Cluster(u32),
+}
+
+/// A file in the root directory.
+pub struct File {
+    cluster: u32,
+    /// The file's size in bytes.
+    pub size: usize,
+}
+
+/// Just enough of the FAT16 or FAT32 file system in the SD card's first
+/// partition, the boot partition, to read files in its root directory.
+pub struct Volume<'a> {
+    sd: &'a mut Sd,
+    fat32: bool,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    data_start: u32,
+    root: Root,
+    /// The sector of the FAT last read into `fat_buf`.
+    fat_sector: Option<u32>,
+    fat_buf: [u8; SECTOR_SIZE],
+}
+
+impl<'a> Volume<'a> {
+    /// Opens the file system in the first partition of `sd`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the card has no MBR, its
+    /// first partition isn't FAT16 or FAT32, or the file system uses sectors
+    /// larger than `SECTOR_SIZE`.
+    pub fn open(sd: &'a mut Sd) -> io::Result<Volume<'a>> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        sd.read_sector(0, &mut buf)?;
+        if buf[510..512] != [0x55, 0xaa] {
+            return ioerr!(InvalidData, "no MBR on the SD card");
+        } else if !FAT_PARTITION_TYPES.contains(&buf[446 + 4]) {
+            return ioerr!(InvalidData, "first partition isn't FAT");
+        }
+
+        let start = u32_at(&buf, 446 + 8);
+        sd.read_sector(start, &mut buf)?;
+        if buf[510..512] != [0x55, 0xaa] || u16_at(&buf, 11) as usize != SECTOR_SIZE {
+            return ioerr!(InvalidData, "unsupported FAT boot sector");
+        }
+
+        let sectors_per_cluster = buf[13] as u32;
+        let fat_start = start + u16_at(&buf, 14) as u32;
+        let fats = buf[16] as u32;
+        let root_entries = u16_at(&buf, 17) as u32;
+        let fat16_size = u16_at(&buf, 22) as u32;
+        if sectors_per_cluster == 0 {
+            return ioerr!(InvalidData, "unsupported FAT boot sector");
+        }
+
+        let volume = if fat16_size != 0 {
+            let root_start = fat_start + fats * fat16_size;
+            let root_count = (root_entries * 32 + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
+            Volume {
+                sd,
+                fat32: false,
+                sectors_per_cluster,
+                fat_start,
+                data_start: root_start + root_count,
+                root: Root::Sectors { start: root_start, count: root_count },
+                fat_sector: None,
+                fat_buf: [0; SECTOR_SIZE],
+            }
+        } else {
+            Volume {
+                sd,
+                fat32: true,
+                sectors_per_cluster,
+                fat_start,
+                data_start: fat_start + fats * u32_at(&buf, 36),
+                root: Root::Cluster(u32_at(&buf, 44)),
+                fat_sector: None,
+                fat_buf: [0; SECTOR_SIZE],
+            }
+        };
+
+        Ok(volume)
+    }
+
+    /// Finds the file `name` in the root directory. `name` is in 8.3 form,
+    /// upper case and padded with spaces, like `KERNEL  BIN`.
+    pub fn find(&mut self, name: &[u8; 11]) -> io::Result<Option<File>> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        let (mut sector, mut left, mut cluster) = match self.root {
+            Root::Sectors { start, count } => (start, count, None),
+            Root::Cluster(cluster) => (self.cluster_start(cluster)?, self.sectors_per_cluster, Some(cluster)),
+        };
+
+        loop {
+            if left == 0 {
+                cluster = match cluster {
+                    Some(cluster) => self.next_cluster(cluster)?,
+                    None => None,
+                };
+                match cluster {
+                    Some(cluster) => sector = self.cluster_start(cluster)?,
+                    None => return Ok(None),
+                }
+                left = self.sectors_per_cluster;
+            }
+
+            self.sd.read_sector(sector, &mut buf)?;
+            for entry in buf.chunks(32) {
+                if entry[0] == 0 {
+                    return Ok(None);
+                } else if entry[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == 0 && entry[..11] == name[..] {
+                    let cluster = (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32;
+                    return Ok(Some(File { cluster, size: u32_at(entry, 28) as usize }));
+                }
+            }
+
+            sector += 1;
+            left -= 1;
+        }
+    }
+
+    /// Reads the whole of `file` into the start of `into`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `into` is shorter than the
+    /// file, and of kind `InvalidData` if the file's cluster chain is broken.
+    pub fn read(&mut self, file: &File, into: &mut [u8]) -> io::Result<()> {
+        if into.len() < file.size {
+            return ioerr!(InvalidInput, "file larger than the buffer");
+        }
+        if file.size == 0 {
+            return Ok(());
+        }
+
+        let mut cluster = file.cluster;
+        let mut read = 0;
+        loop {
+            let start = self.cluster_start(cluster)?;
+            for sector in start..start + self.sectors_per_cluster {
+                let left = file.size - read;
+                if left == 0 {
+                    return Ok(());
+                } else if left >= SECTOR_SIZE {
+                    self.sd.read_sector(sector, &mut into[read..])?;
+                    read += SECTOR_SIZE;
+                } else {
+                    let mut buf = [0u8; SECTOR_SIZE];
+                    self.sd.read_sector(sector, &mut buf)?;
+                    into[read..file.size].copy_from_slice(&buf[..left]);
+                    read = file.size;
+                }
+            }
+
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None if read == file.size => return Ok(()),
+                None => return ioerr!(InvalidData, "FAT cluster chain shorter than the file"),
+            };
+        }
+    }
+
+    /// Returns the first sector of the data cluster `cluster`.
+    fn cluster_start(&self, cluster: u32) -> io::Result<u32> {
+        if cluster < 2 {
+            return ioerr!(InvalidData, "invalid FAT cluster");
+        }
+
+        Ok(self.data_start + (cluster - 2) * self.sectors_per_cluster)
+    }
+
+    /// Returns the cluster following `cluster` in its chain, or `None` if
+    /// `cluster` is the last one.
+    fn next_cluster(&mut self, cluster: u32) -> io::Result<Option<u32>> {
+        let entry_size = if self.fat32 { 4 } else { 2 };
+        let offset = cluster * entry_size;
+        let sector = self.fat_start + offset / SECTOR_SIZE as u32;
+        let at = (offset % SECTOR_SIZE as u32) as usize;
+
+        if self.fat_sector != Some(sector) {
+            self.sd.read_sector(sector, &mut self.fat_buf)?;
+            self.fat_sector = Some(sector);
+        }
+
+        let (next, end) = if self.fat32 {
+            (u32_at(&self.fat_buf, at) & 0x0fffffff, 0x0ffffff8)
+        } else {
+            (u16_at(&self.fat_buf, at) as u32, 0xfff8)
+        };
+
+        if next >= end {
+            Ok(None)
+        } else {
+            Ok(Some(next))
+        }
+    }
+}
@@ -6,20 +6,46 @@
 
 #[cfg(not(test))]
 mod init;
+mod fat;
+mod monitor;
 
-use xmodem::Xmodem;
+use xmodem::{ImageError, ImageHeader, Mode, Resume, Xmodem, IMAGE_HEADER_LEN, IMAGE_MAGIC};
+use xmodem::{BINARY_START_ADDR, MAX_BINARY_SIZE};
+use core::fmt::{self, Write};
 use core::time::Duration;
 use pi;
-
-/// Start address of the binary to load and of the bootloader.
-const BINARY_START_ADDR: usize = 0x80000;
-const BOOTLOADER_START_ADDR: usize = 0x4000000;
+use pi::sd::{Sd, SECTOR_SIZE};
+use pi::timer;
+use pi::uart::MiniUart;
+use shim::io;
 
 /// Pointer to where the loaded binary expects to be laoded.
 const BINARY_START: *mut u8 = BINARY_START_ADDR as *mut u8;
 
-/// Free space between the bootloader and the loaded binary's start address.
-const MAX_BINARY_SIZE: usize = BOOTLOADER_START_ADDR - BINARY_START_ADDR;
+/// How long to wait for the sender before asking again.
+const READ_TIMEOUT: Duration = Duration::from_millis(750);
+
+/// How long to wait for Enter to start the monitor.
+const MONITOR_WINDOW: Duration = Duration::from_secs(1);
+
+/// How long to wait for an XMODEM sender before booting the kernel on the SD
+/// card. The window is checked between handshakes, so it may run a few
+/// seconds longer.
+const SERIAL_WINDOW: Duration = Duration::from_secs(5);
+
+/// Where the kernel on the SD card is, or `None` to only boot over serial.
+const SD_KERNEL: Option<SdKernel> = Some(SdKernel::File(*b"KERNEL  BIN"));
+
+/// Where a kernel is on the SD card.
+#[allow(dead_code)]
+enum SdKernel {
+    /// A file in the root directory of the FAT boot partition, named in 8.3
+    /// form, upper case and padded with spaces. The file is either a framed
+    /// boot image or a plain binary.
+    File([u8; 11]),
+    /// A framed boot image starting at a fixed sector of the card.
+    Sector(u32),
+}
 
 /// Branches to the address `addr` unconditionally.
 unsafe fn jump_to(addr: *mut u8) -> ! {
@@ -29,6 +55,211 @@ unsafe fn jump_to(addr: *mut u8) -> ! {
     }
 }
 
+/// Reasons the bootloader refuses a received boot image.
+#[derive(Debug)]
+enum LoadError {
+    /// The header or the image itself is invalid.
+    Image(ImageError),
+    /// The image must be loaded somewhere other than `BINARY_START_ADDR`.
+    LoadAddress(u64),
+    /// The entry point lies outside of the image.
+    Entry(u64),
+    /// The image of this many bytes doesn't fit below the bootloader.
+    TooLarge(usize),
+    /// Reading the SD card failed.
+    Sd(io::Error),
+    /// The SD card has no kernel file.
+    NotFound,
+}
+
+impl From<ImageError> for LoadError {
+    fn from(e: ImageError) -> LoadError {
+        LoadError::Image(e)
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Sd(e)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Image(e) => e.fmt(f),
+            LoadError::LoadAddress(addr) => {
+                write!(f, "load address {:#x} isn't {:#x}", addr, BINARY_START_ADDR)
+            }
+            LoadError::Entry(addr) => write!(f, "entry point {:#x} outside of the image", addr),
+            LoadError::TooLarge(len) => {
+                write!(f, "image of {} bytes larger than {} bytes", len, MAX_BINARY_SIZE)
+            }
+            LoadError::Sd(ref e) => write!(f, "SD card: {}", e),
+            LoadError::NotFound => write!(f, "no kernel on the SD card"),
+        }
+    }
+}
+
+/// Validates the boot image received at `BINARY_START`, `received` bytes
+/// including its header, and unpacks it to `BINARY_START`, over the header,
+/// decompressing it if it's compressed. Returns the image's entry point.
+///
+/// # Safety
+///
+/// `received` bytes at `BINARY_START` must hold the image, with
+/// `received <= MAX_BINARY_SIZE`; all `MAX_BINARY_SIZE` bytes are written.
+unsafe fn load_image(received: usize) -> Result<*mut u8, LoadError> {
+    let buf = core::slice::from_raw_parts_mut(BINARY_START, MAX_BINARY_SIZE);
+    let header = ImageHeader::decode(&buf[..received])?;
+    if header.load_addr != BINARY_START_ADDR as u64 {
+        return Err(LoadError::LoadAddress(header.load_addr));
+    }
+
+    header.verify(&buf[IMAGE_HEADER_LEN..received])?;
+    let len = header.unpack(buf)?;
+    let end = BINARY_START_ADDR as u64 + len as u64;
+    if header.entry < BINARY_START_ADDR as u64 || header.entry >= end {
+        return Err(LoadError::Entry(header.entry));
+    }
+
+    Ok(header.entry as usize as *mut u8)
+}
+
+/// Receives an image over `uart` into `BINARY_START`, continuing an earlier,
+/// interrupted transfer from `at`, which is updated as packets arrive.
+/// Returns the number of bytes received in total, header included.
+fn receive(uart: &mut MiniUart, at: &mut Resume) -> io::Result<usize> {
+    // The slice ends at the bootloader's stack, so writing an image that
+    // would overflow into the bootloader fails instead.
+    let into = unsafe { core::slice::from_raw_parts_mut(BINARY_START, MAX_BINARY_SIZE) };
+    if *at != Resume::default() {
+        // Discard what's left of the interrupted transfer before the
+        // handshake, until the line is quiet for `READ_TIMEOUT`.
+        while uart.wait_for_byte().is_ok() {
+            uart.read_byte();
+        }
+    }
+    Xmodem::receive_resumable(uart, &mut into[at.offset()..], Mode::Crc, |_| {}, at)?;
+    Ok(at.offset())
+}
+
+/// Loads the kernel `kernel` from the SD card to `BINARY_START`. Returns its
+/// entry point.
+fn load_sd(kernel: &SdKernel) -> Result<*mut u8, LoadError> {
+    let into = unsafe { core::slice::from_raw_parts_mut(BINARY_START, MAX_BINARY_SIZE) };
+    let mut sd = Sd::new()?;
+    match *kernel {
+        SdKernel::File(ref name) => {
+            let mut volume = fat::Volume::open(&mut sd)?;
+            let file = volume.find(name)?.ok_or(LoadError::NotFound)?;
+            if file.size > MAX_BINARY_SIZE {
+                return Err(LoadError::TooLarge(file.size));
+            }
+
+            volume.read(&file, into)?;
+            if into.starts_with(&IMAGE_MAGIC) {
+                unsafe { load_image(file.size) }
+            } else {
+                Ok(BINARY_START)
+            }
+        }
+        SdKernel::Sector(start) => {
+            sd.read_sector(start, into)?;
+            let len = IMAGE_HEADER_LEN + ImageHeader::decode(into)?.len;
+            if len > MAX_BINARY_SIZE {
+                return Err(LoadError::TooLarge(len));
+            }
+
+            let sectors = (len + SECTOR_SIZE - 1) / SECTOR_SIZE;
+            for i in 1..sectors {
+                sd.read_sector(start + i as u32, &mut into[i * SECTOR_SIZE..])?;
+            }
+            unsafe { load_image(len) }
+        }
+    }
+}
+
+/// Jumps to the entry point of the image loaded from `source`, or reports why
+/// the image was rejected.
+fn boot(uart: &mut MiniUart, source: &str, entry: Result<*mut u8, LoadError>) {
+    match entry {
+        Ok(entry) => {
+            let _ = writeln!(uart, "boot: jumping to {:p}, loaded from {}", entry, source);
+            unsafe { jump_to(entry) }
+        }
+        Err(e) => {
+            let _ = writeln!(uart, "boot: rejected image from {}: {}", source, e);
+        }
+    }
+}
+
+/// Returns `true` if Enter is pressed within `MONITOR_WINDOW`.
+fn monitor_requested(uart: &mut MiniUart) -> bool {
+    let _ = writeln!(uart, "boot: press Enter for the monitor");
+    let start = timer::current_time();
+    while timer::current_time() - start < MONITOR_WINDOW {
+        if uart.has_byte() {
+            match uart.read_byte() {
+                b'\r' | b'\n' => return true,
+                _ => {}
+            }
+        }
+    }
+
+    false
+}
+
 fn kmain() -> ! {
-    // FIXME: Implement the bootloader.
+    let mut uart = MiniUart::new();
+    uart.set_read_timeout(READ_TIMEOUT);
+    if monitor_requested(&mut uart) {
+        monitor::run(&mut uart);
+    }
+    let _ = writeln!(uart, "boot: waiting for an image at {:#x}", BINARY_START_ADDR);
+
+    // The SD card is tried once, after the window, unless a serial transfer
+    // is waiting to be resumed; serial uploads are accepted before and after.
+    let start = timer::current_time();
+    let mut sd_kernel = SD_KERNEL;
+    // Where an interrupted transfer stopped, for `ttywrite -R` to resume it.
+    let mut at = Resume::default();
+    loop {
+        let resumed_at = at;
+        let received = match receive(&mut uart, &mut at) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                if at == Resume::default() && timer::current_time() - start >= SERIAL_WINDOW {
+                    if let Some(kernel) = sd_kernel.take() {
+                        boot(&mut uart, "the SD card", load_sd(&kernel));
+                    }
+                }
+                continue;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {
+                let _ = writeln!(uart, "boot: image larger than {} bytes", MAX_BINARY_SIZE);
+                at = Resume::default();
+                continue;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData
+                && resumed_at != Resume::default() && at == resumed_at => {
+                // The sender started a new transfer instead of resuming.
+                let _ = writeln!(uart, "boot: sender didn't resume at block {}; starting over", at.block());
+                at = Resume::default();
+                continue;
+            }
+            Err(e) => {
+                let _ = writeln!(uart, "boot: transfer failed at block {}: {}", at.block(), e);
+                continue;
+            }
+        };
+
+        at = Resume::default();
+        let received_image = unsafe { core::slice::from_raw_parts(BINARY_START, received) };
+        if received_image.starts_with(&IMAGE_MAGIC) {
+            boot(&mut uart, "serial", unsafe { load_image(received) });
+        } else {
+            boot(&mut uart, "serial", Ok(BINARY_START));
+        }
+    }
 }
@@ -0,0 +1,171 @@
+use core::fmt::Write;
+
+use pi::uart::MiniUart;
+use shim::io;
+use xmodem::{get_crc32, Mode, Xmodem, XmodemConfig, BOOTLOADER_STACK_SIZE, BOOTLOADER_START_ADDR};
+
+use crate::jump_to;
+
+/// Longest command line.
+const LINE_LEN: usize = 128;
+
+/// Uploads must end below the bootloader's stack.
+const UPLOAD_END: usize = BOOTLOADER_START_ADDR - BOOTLOADER_STACK_SIZE;
+
+/// Number of handshakes an upload waits for a sender, about 30 seconds.
+const UPLOAD_HANDSHAKES: usize = 40;
+
+const HELP: &str = "\
+commands, with numbers in decimal or 0x-prefixed hex:
+  peek <addr>          show the word at <addr>
+  poke <addr> <word>   write <word> to <addr>
+  dump <addr> <len>    show the words of <len> bytes from <addr>
+  load <addr>          receive a file with XMODEM to <addr>
+  crc <addr> <len>     show the CRC-32 of <len> bytes from <addr>
+  go <addr>            jump to <addr>
+  boot                 leave the monitor and wait for an image
+";
+
+/// Reads a line of printable characters into `buf`, echoing it.
+fn read_line<'a>(uart: &mut MiniUart, buf: &'a mut [u8; LINE_LEN]) -> &'a str {
+    let mut len = 0;
+    loop {
+        match uart.read_byte() {
+            b'\r' | b'\n' => break,
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                let _ = uart.write_str("\x08 \x08");
+            }
+            byte @ b' '..=b'~' if len < LINE_LEN => {
+                buf[len] = byte;
+                len += 1;
+                uart.write_byte(byte);
+            }
+            _ => {}
+        }
+    }
+
+    let _ = uart.write_str("\n");
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// Parses the number `arg`, in decimal or in hex with a `0x` prefix.
+fn parse(arg: Option<&str>) -> Result<usize, &'static str> {
+    let arg = arg.ok_or("missing argument")?;
+    let number = if arg.starts_with("0x") {
+        usize::from_str_radix(&arg[2..], 16)
+    } else {
+        arg.parse()
+    };
+
+    number.map_err(|_| "invalid number")
+}
+
+/// Returns a pointer to the word at `addr`.
+fn word(addr: usize) -> Result<*mut u32, &'static str> {
+    if addr % 4 != 0 {
+        return Err("address isn't word aligned");
+    }
+
+    Ok(addr as *mut u32)
+}
+
+/// Shows the words of `len` bytes from `addr`, four per line.
+fn dump(uart: &mut MiniUart, addr: usize, len: usize) -> Result<(), &'static str> {
+    let start = word(addr)?;
+    addr.checked_add(len).ok_or("range out of bounds")?;
+
+    let words = (len + 3) / 4;
+    for i in 0..words {
+        if i % 4 == 0 {
+            let _ = write!(uart, "{}{:#010x}:", if i == 0 { "" } else { "\n" }, addr + i * 4);
+        }
+        let _ = write!(uart, " {:08x}", unsafe { start.add(i).read_volatile() });
+    }
+
+    if words > 0 {
+        let _ = uart.write_str("\n");
+    }
+    Ok(())
+}
+
+/// Receives a file with XMODEM to `addr`, without overwriting the bootloader.
+fn load(uart: &mut MiniUart, addr: usize) -> Result<(), &'static str> {
+    if addr >= UPLOAD_END {
+        return Err("address in the bootloader's region");
+    }
+
+    let into = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, UPLOAD_END - addr) };
+    let config = XmodemConfig { handshake_retries: UPLOAD_HANDSHAKES, ..XmodemConfig::default() };
+    let result = {
+        let mut xmodem = Xmodem::new_with_config(&mut *uart, config);
+        xmodem.set_mode(Mode::Crc);
+        xmodem.receive_data(into, None)
+    };
+
+    match result {
+        Ok(received) => {
+            let _ = writeln!(uart, "received {} bytes at {:#x}", received, addr);
+            Ok(())
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WriteZero => Err("file overflows into the bootloader"),
+        Err(e) => {
+            let _ = writeln!(uart, "upload failed: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Runs the command `line`. Returns `false` if the monitor should exit.
+fn command(uart: &mut MiniUart, line: &str) -> Result<bool, &'static str> {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        None => {}
+        Some("peek") => {
+            let addr = word(parse(args.next())?)?;
+            let value = unsafe { addr.read_volatile() };
+            let _ = writeln!(uart, "{:#010x}: {:#010x}", addr as usize, value);
+        }
+        Some("poke") => {
+            let addr = word(parse(args.next())?)?;
+            let value = parse(args.next())?;
+            if value > u32::MAX as usize {
+                return Err("value larger than a word");
+            }
+            unsafe { addr.write_volatile(value as u32) };
+        }
+        Some("dump") => dump(uart, parse(args.next())?, parse(args.next())?)?,
+        Some("load") => load(uart, parse(args.next())?)?,
+        Some("crc") => {
+            let (addr, len) = (parse(args.next())?, parse(args.next())?);
+            addr.checked_add(len).ok_or("range out of bounds")?;
+            let data = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+            let _ = writeln!(uart, "{:#010x}", get_crc32(data));
+        }
+        Some("go") => unsafe { jump_to(parse(args.next())? as *mut u8) },
+        Some("boot") => return Ok(false),
+        Some("help") => {
+            let _ = uart.write_str(HELP);
+        }
+        Some(_) => return Err("unknown command, try help"),
+    }
+
+    Ok(true)
+}
+
+/// Runs the monitor on `uart` until the `boot` command.
+pub fn run(uart: &mut MiniUart) {
+    let _ = uart.write_str("monitor: type help for commands\n");
+    let mut buf = [0u8; LINE_LEN];
+    loop {
+        let _ = uart.write_str("> ");
+        let line = read_line(uart, &mut buf);
+        match command(uart, line) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(msg) => {
+                let _ = writeln!(uart, "error: {}", msg);
+            }
+        }
+    }
+}
@@ -1,62 +1,139 @@
 use core::fmt;
+use core::time::Duration;
 use pi::uart::MiniUart;
 use shim::io;
 
 use crate::mutex::Mutex;
 
+/// Size of the console's output buffer, in bytes.
+const OUTPUT_LEN: usize = 1024;
+
 /// A global singleton allowing read/write access to the console.
+///
+/// Output is buffered and sent to the UART as its FIFO has space, so writes
+/// only block when the buffer is full or a line ends. The buffer is sent in
+/// full at the end of each line, before reading, and by `io::Write::flush`.
 pub struct Console {
     inner: Option<MiniUart>,
+    /// The read timeout, set on the UART when it's initialized.
+    timeout: Option<Duration>,
+    /// A ring of bytes waiting to be sent, `pending` of them from `start`.
+    output: [u8; OUTPUT_LEN],
+    start: usize,
+    pending: usize,
 }
 
 impl Console {
     /// Creates a new instance of `Console`.
     const fn new() -> Console {
-        Console { inner: None }
+        Console { inner: None, timeout: None, output: [0; OUTPUT_LEN], start: 0, pending: 0 }
     }
 
     /// Initializes the console if it's not already initialized.
     #[inline]
     fn initialize(&mut self) {
-        unimplemented!()
+        if self.inner.is_none() {
+            let mut uart = MiniUart::new();
+            if let Some(t) = self.timeout {
+                uart.set_read_timeout(t);
+            }
+            self.inner = Some(uart);
+        }
     }
 
     /// Returns a mutable borrow to the inner `MiniUart`, initializing it as
     /// needed.
     fn inner(&mut self) -> &mut MiniUart {
-        unimplemented!()
+        self.initialize();
+        match self.inner {
+            Some(ref mut uart) => uart,
+            None => unreachable!("console not initialized"),
+        }
+    }
+
+    /// Sets the read timeout of `io::Read::read` to `t`. `read_byte` always
+    /// blocks until a byte is available.
+    pub fn set_read_timeout(&mut self, t: Duration) {
+        self.timeout = Some(t);
+        if let Some(ref mut uart) = self.inner {
+            uart.set_read_timeout(t);
+        }
+    }
+
+    /// Sends the oldest buffered byte, which there must be, to the UART.
+    fn send_byte(&mut self) {
+        let byte = self.output[self.start];
+        self.inner().write_byte(byte);
+        self.start = (self.start + 1) % OUTPUT_LEN;
+        self.pending -= 1;
+    }
+
+    /// Sends buffered bytes to the UART until they're all sent, or, unless
+    /// `block` is set, until its output FIFO is full.
+    fn send(&mut self, block: bool) {
+        while self.pending > 0 {
+            if !block && !self.inner().can_write() {
+                return;
+            }
+            self.send_byte();
+        }
+    }
+
+    /// Adds `byte` to the output buffer, waiting for room if it's full.
+    fn buffer(&mut self, byte: u8) {
+        if self.pending == OUTPUT_LEN {
+            self.send_byte();
+        }
+
+        self.output[(self.start + self.pending) % OUTPUT_LEN] = byte;
+        self.pending += 1;
     }
 
     /// Reads a byte from the UART device, blocking until a byte is available.
     pub fn read_byte(&mut self) -> u8 {
-        unimplemented!()
+        self.send(true);
+        self.inner().read_byte()
     }
 
-    /// Writes the byte `byte` to the UART device.
+    /// Writes the byte `byte` to the UART device, preceding a `\n` with a
+    /// `\r`. A `\n` waits for the whole line to be sent, so printed lines
+    /// reach the UART even if nothing is written or read afterwards.
     pub fn write_byte(&mut self, byte: u8) {
-        unimplemented!()
+        if byte == b'\n' {
+            self.buffer(b'\r');
+        }
+        self.buffer(byte);
+        self.send(byte == b'\n');
     }
 }
 
 impl io::Read for Console {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        unimplemented!()
+        self.send(true);
+        io::Read::read(self.inner(), buf)
     }
 }
 
 impl io::Write for Console {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        unimplemented!()
+        for &byte in buf {
+            self.write_byte(byte);
+        }
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.send(true);
         Ok(())
     }
 }
 
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        unimplemented!()
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
     }
 }
 
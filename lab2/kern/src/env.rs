@@ -0,0 +1,121 @@
+use core::str;
+
+use crate::mutex::Mutex;
+
+/// Largest number of variables in the environment.
+const MAX_VARS: usize = 32;
+
+/// Longest variable name, in bytes.
+const NAME_LEN: usize = 32;
+
+/// Longest variable value, in bytes.
+const VALUE_LEN: usize = 128;
+
+/// Error type for `Env::set` failures.
+#[derive(Debug)]
+pub enum Error {
+    InvalidName,
+    TooLong,
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Var {
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    value: [u8; VALUE_LEN],
+    value_len: usize,
+}
+
+impl Var {
+    /// An unused slot, which has an empty name.
+    const EMPTY: Var = Var { name: [0; NAME_LEN], name_len: 0, value: [0; VALUE_LEN], value_len: 0 };
+
+    fn is_empty(&self) -> bool {
+        self.name_len == 0
+    }
+
+    fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn value(&self) -> &str {
+        str::from_utf8(&self.value[..self.value_len]).unwrap_or("")
+    }
+}
+
+/// The kernel's environment variables. They're kept in fixed-size storage so
+/// the shell can use them before the heap exists.
+pub struct Env {
+    vars: [Var; MAX_VARS],
+}
+
+impl Env {
+    const fn new() -> Env {
+        Env { vars: [Var::EMPTY; MAX_VARS] }
+    }
+
+    /// Returns the value of the variable `name`, if it's set.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.iter().find(|var| !var.is_empty() && var.name() == name).map(|var| var.value())
+    }
+
+    /// Sets the variable `name` to `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidName` if `name` isn't a valid name,
+    /// `Error::TooLong` if `name` or `value` don't fit in a variable, and
+    /// `Error::Full` if `name` isn't set and no more variables fit.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName);
+        } else if name.len() > NAME_LEN || value.len() > VALUE_LEN {
+            return Err(Error::TooLong);
+        }
+
+        let var = match self.vars.iter().position(|var| !var.is_empty() && var.name() == name) {
+            Some(i) => &mut self.vars[i],
+            None => self.vars.iter_mut().find(|var| var.is_empty()).ok_or(Error::Full)?,
+        };
+
+        var.name[..name.len()].copy_from_slice(name.as_bytes());
+        var.name_len = name.len();
+        var.value[..value.len()].copy_from_slice(value.as_bytes());
+        var.value_len = value.len();
+        Ok(())
+    }
+
+    /// Removes the variable `name`. Returns `false` if it wasn't set.
+    pub fn unset(&mut self, name: &str) -> bool {
+        match self.vars.iter_mut().find(|var| !var.is_empty() && var.name() == name) {
+            Some(var) => {
+                *var = Var::EMPTY;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns an iterator over the names and values of the variables.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().filter(|var| !var.is_empty()).map(|var| (var.name(), var.value()))
+    }
+}
+
+/// Returns `true` if `c` may appear in a variable name.
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Returns `true` if `name` is a valid variable name: a letter or `_`
+/// followed by letters, digits and `_`.
+pub fn is_valid_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(first) if !first.is_ascii_digit() => name.chars().all(is_name_char),
+        _ => false,
+    }
+}
+
+/// Global kernel environment.
+pub static ENV: Mutex<Env> = Mutex::new(Env::new());
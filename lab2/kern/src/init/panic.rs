@@ -1,6 +1,12 @@
 use core::panic::PanicInfo;
 
+use shim::io::Write;
+
+use crate::console::CONSOLE;
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    // Send the output that led up to the panic before halting.
+    let _ = CONSOLE.lock().flush();
     loop {}
 }
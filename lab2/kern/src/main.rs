@@ -10,7 +10,6 @@
 //----------------------------
 // pi 
 #![feature(core_intrinsics)]
-#![no_std]
 #![feature(never_type)]
 //----------------------------
 
@@ -18,21 +17,21 @@
 mod init;
 
 pub mod console;
+pub mod env;
 pub mod mutex;
 pub mod shell;
 
 use console::kprintln;
 
-use pi::uart::MiniUart;
-use core::fmt::Write;
 use pi::timer::spin_sleep;
 use core::time::Duration;
 
 unsafe fn kmain() -> ! {
-    let mut mu = MiniUart::new();
+    // Give the other end of the serial line time to attach before the first
+    // prompt.
+    spin_sleep(Duration::from_millis(500));
     loop {
-        let read = mu.read_byte();
-        mu.write_byte(read);
-        mu.write_str("<-");
+        shell::shell("> ");
+        kprintln!("shell exited, starting a new one");
     }
 }
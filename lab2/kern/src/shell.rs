@@ -1,12 +1,45 @@
 use stack_vec::StackVec;
 
+use pi::timer;
+use shim::io;
+
 use crate::console::{kprint, kprintln, CONSOLE};
+use crate::env::{self, ENV};
+
+/// Longest command line, in bytes.
+const LINE_LEN: usize = 512;
+
+/// Longest command after expanding variables, in bytes.
+const WORDS_LEN: usize = 1024;
+
+/// Largest number of arguments in a command, its path included.
+const MAX_ARGS: usize = 64;
+
+/// Number of lines kept in the history.
+const HISTORY_LEN: usize = 16;
+
+/// Largest number of completions offered for a word.
+const MAX_COMPLETIONS: usize = 32;
+
+// Bytes the shell reads and writes.
+const CTRL_A: u8 = 0x01;
+const CTRL_E: u8 = 0x05;
+const BELL: u8 = 0x07;
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const CTRL_K: u8 = 0x0b;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
 
 /// Error type for `Command` parse failures.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Error {
     Empty,
     TooManyArgs,
+    TooLong,
+    Unterminated,
 }
 
 /// A structure representing a single shell command.
@@ -14,18 +47,125 @@ struct Command<'a> {
     args: StackVec<'a, &'a str>,
 }
 
+/// Appends to the buffer a command's words are written to.
+struct Words<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Words<'a> {
+    fn push(&mut self, s: &str) -> Result<(), Error> {
+        let bytes = s.as_bytes();
+        self.buf.get_mut(self.len..self.len + bytes.len()).ok_or(Error::TooLong)?.copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn push_char(&mut self, c: char) -> Result<(), Error> {
+        self.push(c.encode_utf8(&mut [0; 4]))
+    }
+}
+
 impl<'a> Command<'a> {
-    /// Parse a command from a string `s` using `buf` as storage for the
-    /// arguments.
+    /// Parses the command at the start of `*line`, up to an unquoted `;`,
+    /// and advances `*line` past it. The command's arguments are written to
+    /// `words`, and `buf` is used as storage for them.
+    ///
+    /// Arguments are separated by spaces and tabs. Within single quotes all
+    /// characters are literal. Within double quotes, a backslash escapes `"`,
+    /// `\` and `$`, and `$NAME` or `${NAME}` is replaced by the value of the
+    /// environment variable `NAME`, or by nothing if it isn't set. Outside
+    /// quotes, a backslash escapes any character and variables are expanded
+    /// too, their values not being split into arguments.
     ///
     /// # Errors
     ///
-    /// If `s` contains no arguments, returns `Error::Empty`. If there are more
-    /// arguments than `buf` can hold, returns `Error::TooManyArgs`.
-    fn parse(s: &'a str, buf: &'a mut [&'a str]) -> Result<Command<'a>, Error> {
+    /// If the command contains no arguments, returns `Error::Empty`. If there
+    /// are more arguments than `buf` can hold, returns `Error::TooManyArgs`,
+    /// and if they don't fit in `words`, `Error::TooLong`. If a quote or a
+    /// `${` isn't closed, returns `Error::Unterminated`.
+    fn parse(line: &mut &str, words: &'a mut [u8], buf: &'a mut [&'a str]) -> Result<Command<'a>, Error> {
+        let mut ranges_buf = [(0, 0); MAX_ARGS];
+        let mut ranges = StackVec::new(&mut ranges_buf[..buf.len().min(MAX_ARGS)]);
+        let mut out = Words { buf: words, len: 0 };
+        // The start in `out` of the argument being read, if any.
+        let mut arg = None;
+        let mut quote = None;
+        let mut end = line.len();
+
+        let env = ENV.lock();
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let start = out.len;
+            match (quote, c) {
+                (None, ' ') | (None, '\t') | (None, ';') => {
+                    if let Some(arg) = arg.take() {
+                        ranges.push((arg, out.len)).map_err(|_| Error::TooManyArgs)?;
+                    }
+                    if c == ';' {
+                        end = i + 1;
+                        break;
+                    }
+                    continue;
+                }
+                (None, '\'') | (None, '"') => {
+                    quote = Some(c);
+                    arg.get_or_insert(start);
+                }
+                (Some(q), c) if c == q => quote = None,
+                (Some('\''), c) => out.push_char(c)?,
+                (None, '\\') | (Some('"'), '\\') => match chars.next() {
+                    Some((_, c)) if quote.is_none() || c == '"' || c == '\\' || c == '$' => out.push_char(c)?,
+                    Some((_, c)) => {
+                        out.push_char('\\')?;
+                        out.push_char(c)?;
+                    }
+                    None => out.push_char('\\')?,
+                },
+                (_, '$') => {
+                    let braced = chars.peek().map(|&(_, c)| c) == Some('{');
+                    if braced {
+                        chars.next();
+                    }
+
+                    let name_start = chars.peek().map_or(line.len(), |&(j, _)| j);
+                    let mut name_end = name_start;
+                    while let Some(&(j, c)) = chars.peek() {
+                        if !env::is_name_char(c) {
+                            break;
+                        }
+                        name_end = j + c.len_utf8();
+                        chars.next();
+                    }
+
+                    if braced && chars.next().map(|(_, c)| c) != Some('}') {
+                        return Err(Error::Unterminated);
+                    } else if name_start == name_end && !braced {
+                        out.push_char('$')?;
+                    } else {
+                        out.push(env.get(&line[name_start..name_end]).unwrap_or(""))?;
+                    }
+                }
+                (_, c) => out.push_char(c)?,
+            }
+
+            if out.len > start {
+                arg.get_or_insert(start);
+            }
+        }
+
+        if quote.is_some() {
+            return Err(Error::Unterminated);
+        } else if let Some(arg) = arg {
+            ranges.push((arg, out.len)).map_err(|_| Error::TooManyArgs)?;
+        }
+        *line = &line[end..];
+
+        // Only whole characters are ever written, so the words are UTF-8.
+        let words: &'a [u8] = out.buf;
         let mut args = StackVec::new(buf);
-        for arg in s.split(' ').filter(|a| !a.is_empty()) {
-            args.push(arg).map_err(|_| Error::TooManyArgs)?;
+        for &(start, end) in ranges.iter() {
+            args.push(core::str::from_utf8(&words[start..end]).unwrap_or("")).map_err(|_| Error::TooManyArgs)?;
         }
 
         if args.is_empty() {
@@ -37,12 +177,634 @@ impl<'a> Command<'a> {
 
     /// Returns this command's path. This is equivalent to the first argument.
     fn path(&self) -> &str {
-        unimplemented!()
+        self.args[0]
+    }
+}
+
+/// What the shell does after running a command.
+enum Flow {
+    Continue,
+    Exit,
+}
+
+/// A command built into the shell.
+struct Builtin {
+    /// The path that runs the command.
+    name: &'static str,
+    /// A line describing the command for `help`.
+    help: &'static str,
+    run: fn(&Command) -> Flow,
+    /// Offers completions of the command's arguments, given the arguments
+    /// before the one being completed, the command's path included. A
+    /// command taking paths completes them from the file system here.
+    complete: Option<fn(&[&str], &mut Completions)>,
+}
+
+/// The shell's builtin commands. A command is added by adding its entry here.
+const BUILTINS: &[Builtin] = &[
+    Builtin { name: "echo", help: "print the arguments", run: echo, complete: None },
+    Builtin { name: "help", help: "describe the builtin commands", run: help, complete: Some(complete_help) },
+    Builtin { name: "exit", help: "leave the shell", run: exit, complete: None },
+    Builtin { name: "uptime", help: "show the time since boot", run: uptime, complete: None },
+    Builtin { name: "clear", help: "clear the screen", run: clear, complete: None },
+    Builtin { name: "set", help: "set an environment variable: set <name> <value>", run: set, complete: None },
+    Builtin { name: "unset", help: "remove environment variables: unset <name>...", run: unset, complete: None },
+    Builtin { name: "env", help: "list the environment variables", run: env, complete: None },
+];
+
+/// Returns the builtin command named `name`.
+fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// The completions of a word.
+struct Completions<'a> {
+    word: &'a str,
+    matches: StackVec<'a, &'a str>,
+}
+
+impl<'a> Completions<'a> {
+    /// Offers `candidate` as a completion, which it is if it starts with the
+    /// word. Completions past `MAX_COMPLETIONS` are dropped.
+    fn offer(&mut self, candidate: &'a str) {
+        if candidate.starts_with(self.word) {
+            let _ = self.matches.push(candidate);
+        }
+    }
+
+    /// Returns the longest prefix the completions share.
+    fn common_prefix(&self) -> &'a str {
+        let first = match self.matches.first() {
+            Some(first) => *first,
+            None => return "",
+        };
+
+        let len = self.matches.iter().fold(first.len(), |len, candidate| {
+            first.bytes().zip(candidate.bytes()).take(len).take_while(|(a, b)| a == b).count()
+        });
+        &first[..len]
+    }
+}
+
+fn complete_commands(completions: &mut Completions) {
+    for builtin in BUILTINS {
+        completions.offer(builtin.name);
+    }
+}
+
+fn echo(cmd: &Command) -> Flow {
+    for (i, arg) in cmd.args[1..].iter().enumerate() {
+        kprint!("{}{}", if i == 0 { "" } else { " " }, arg);
+    }
+
+    kprintln!();
+    Flow::Continue
+}
+
+fn help(cmd: &Command) -> Flow {
+    match cmd.args.get(1) {
+        Some(name) => match builtin(name) {
+            Some(builtin) => kprintln!("{}: {}", builtin.name, builtin.help),
+            None => kprintln!("help: no builtin command {}", name),
+        },
+        None => {
+            for builtin in BUILTINS {
+                kprintln!("  {:<8} {}", builtin.name, builtin.help);
+            }
+        }
+    }
+
+    Flow::Continue
+}
+
+fn complete_help(args: &[&str], completions: &mut Completions) {
+    if args.len() == 1 {
+        complete_commands(completions);
+    }
+}
+
+fn exit(_: &Command) -> Flow {
+    Flow::Exit
+}
+
+fn uptime(_: &Command) -> Flow {
+    let now = timer::current_time();
+    kprintln!("up {}.{:03}s", now.as_secs(), now.subsec_millis());
+    Flow::Continue
+}
+
+fn clear(_: &Command) -> Flow {
+    kprint!("\x1b[2J\x1b[H");
+    Flow::Continue
+}
+
+fn set(cmd: &Command) -> Flow {
+    if cmd.args.len() != 3 {
+        kprintln!("usage: set <name> <value>");
+        return Flow::Continue;
+    }
+
+    match ENV.lock().set(cmd.args[1], cmd.args[2]) {
+        Ok(()) => {}
+        Err(env::Error::InvalidName) => kprintln!("set: invalid variable name {}", cmd.args[1]),
+        Err(env::Error::TooLong) => kprintln!("set: name or value too long"),
+        Err(env::Error::Full) => kprintln!("set: environment full"),
+    }
+
+    Flow::Continue
+}
+
+fn unset(cmd: &Command) -> Flow {
+    let mut env = ENV.lock();
+    for name in cmd.args[1..].iter() {
+        if !env.unset(name) {
+            kprintln!("unset: {} isn't set", name);
+        }
+    }
+
+    Flow::Continue
+}
+
+fn env(_: &Command) -> Flow {
+    for (name, value) in ENV.lock().iter() {
+        kprintln!("{}={}", name, value);
+    }
+
+    Flow::Continue
+}
+
+fn bell() {
+    CONSOLE.lock().write_byte(BELL);
+}
+
+/// Writes `bytes`, all printable, to the console.
+fn write(bytes: &[u8]) {
+    let mut console = CONSOLE.lock();
+    for &byte in bytes {
+        console.write_byte(byte);
+    }
+}
+
+/// Moves the terminal's cursor `n` columns left.
+fn cursor_left(n: usize) {
+    if n > 0 {
+        kprint!("\x1b[{}D", n);
+    }
+}
+
+/// Moves the terminal's cursor `n` columns right.
+fn cursor_right(n: usize) {
+    if n > 0 {
+        kprint!("\x1b[{}C", n);
+    }
+}
+
+/// A line being edited, mirrored on the terminal after the prompt.
+struct Line {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    cursor: usize,
+}
+
+impl Line {
+    fn new() -> Line {
+        Line { buf: [0; LINE_LEN], len: 0, cursor: 0 }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns the line. Only printable ASCII is ever inserted, so the line
+    /// is always valid UTF-8.
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+
+    /// Redraws the line from the cursor to its end, clearing anything left
+    /// behind it, and leaves the terminal's cursor where it was.
+    fn redraw_tail(&self) {
+        write(&self.buf[self.cursor..self.len]);
+        kprint!("\x1b[K");
+        cursor_left(self.len - self.cursor);
+    }
+
+    /// Inserts `byte` at the cursor, ringing the bell if the line is full.
+    fn insert(&mut self, byte: u8) {
+        if self.len == LINE_LEN {
+            return bell();
+        }
+
+        self.buf.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.buf[self.cursor] = byte;
+        self.len += 1;
+        write(&[byte]);
+        self.cursor += 1;
+        self.redraw_tail();
+    }
+
+    /// Removes the bytes in `start..end`, leaving the cursor at `start`.
+    fn remove(&mut self, start: usize, end: usize) {
+        if start == end {
+            return bell();
+        }
+
+        cursor_left(self.cursor - start);
+        self.buf.copy_within(end..self.len, start);
+        self.len -= end - start;
+        self.cursor = start;
+        self.redraw_tail();
+    }
+
+    /// Removes the word before the cursor and the spaces following it.
+    fn remove_word(&mut self) {
+        let line = &self.buf[..self.cursor];
+        let end = line.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        let start = line[..end].iter().rposition(|&b| b == b' ').map_or(0, |i| i + 1);
+        self.remove(start, self.cursor);
+    }
+
+    /// Moves the cursor to `to`, ringing the bell if it's already there.
+    fn move_to(&mut self, to: usize) {
+        if to == self.cursor {
+            return bell();
+        } else if to < self.cursor {
+            cursor_left(self.cursor - to);
+        } else {
+            cursor_right(to - self.cursor);
+        }
+
+        self.cursor = to;
+    }
+
+    /// Replaces the line with `bytes`, leaving the cursor at its end.
+    fn replace(&mut self, bytes: &[u8]) {
+        cursor_left(self.cursor);
+        self.buf[..bytes.len()].copy_from_slice(bytes);
+        self.len = bytes.len();
+        self.cursor = 0;
+        self.redraw_tail();
+        cursor_right(self.len);
+        self.cursor = self.len;
+    }
+}
+
+/// The last `HISTORY_LEN` lines entered, in a ring.
+struct History {
+    lines: [[u8; LINE_LEN]; HISTORY_LEN],
+    lens: [usize; HISTORY_LEN],
+    /// The index in `lines` of the next line entered.
+    next: usize,
+    /// The number of lines in the history.
+    count: usize,
+}
+
+impl History {
+    fn new() -> History {
+        History { lines: [[0; LINE_LEN]; HISTORY_LEN], lens: [0; HISTORY_LEN], next: 0, count: 0 }
+    }
+
+    /// Returns the line entered `back` lines ago, `1` being the last one.
+    fn get(&self, back: usize) -> Option<&[u8]> {
+        if back == 0 || back > self.count {
+            return None;
+        }
+
+        let i = (self.next + HISTORY_LEN - back) % HISTORY_LEN;
+        Some(&self.lines[i][..self.lens[i]])
+    }
+
+    /// Adds `line` to the history, dropping the oldest line if the history is
+    /// full. Blank lines and repeats of the last line aren't added.
+    fn push(&mut self, line: &[u8]) {
+        if line.iter().all(|&b| b == b' ') || self.get(1) == Some(line) {
+            return;
+        }
+
+        self.lines[self.next][..line.len()].copy_from_slice(line);
+        self.lens[self.next] = line.len();
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = (self.count + 1).min(HISTORY_LEN);
+    }
+}
+
+/// Completes the word before the cursor in `line`, a command's path or one
+/// of its arguments. Adds what all completions share to the word, and a
+/// space after it if there's one completion. If there's nothing to add and
+/// `list` is set, lists the completions and redraws the line after `prompt`.
+///
+/// Returns `true` if the word was extended.
+fn complete(line: &mut Line, prompt: &str, list: bool) -> bool {
+    // The line is copied so it can be edited while completions borrow it.
+    let typed = line.buf;
+    let before = core::str::from_utf8(&typed[..line.cursor]).unwrap_or("");
+    let before = &before[before.rfind(';').map_or(0, |i| i + 1)..];
+    let start = before.rfind(' ').map_or(0, |i| i + 1);
+
+    let mut args_buf = [""; MAX_ARGS];
+    let mut args = StackVec::new(&mut args_buf);
+    for arg in before[..start].split(' ').filter(|a| !a.is_empty()) {
+        if args.push(arg).is_err() {
+            bell();
+            return false;
+        }
+    }
+
+    let mut matches_buf = [""; MAX_COMPLETIONS];
+    let mut completions = Completions { word: &before[start..], matches: StackVec::new(&mut matches_buf) };
+    if args.is_empty() {
+        complete_commands(&mut completions);
+    } else if let Some(complete) = builtin(args[0]).and_then(|builtin| builtin.complete) {
+        complete(&args, &mut completions);
+    }
+
+    let common = completions.common_prefix();
+    let added = &common.as_bytes()[completions.word.len().min(common.len())..];
+    for &byte in added {
+        line.insert(byte);
+    }
+
+    if completions.matches.len() == 1 {
+        line.insert(b' ');
+        return true;
+    } else if !added.is_empty() {
+        return true;
+    } else if completions.matches.is_empty() || !list {
+        bell();
+        return false;
+    }
+
+    kprintln!();
+    completions.matches.sort_unstable();
+    for candidate in completions.matches.iter() {
+        kprint!("{}  ", candidate);
+    }
+    kprintln!();
+    kprint!("{}", prompt);
+    write(line.as_bytes());
+    cursor_left(line.len - line.cursor);
+    false
+}
+
+/// A key, or keys, that edit the line.
+enum Key {
+    Char(u8),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillToEnd,
+    KillToStart,
+    KillWord,
+    Unknown,
+}
+
+/// Reads the rest of an ANSI escape sequence whose `ESC` has been read.
+/// Understands the cursor keys' `ESC [ x` and `ESC O x` forms, and the
+/// `ESC [ n ~` form of home, end and delete. Other sequences, like the
+/// modified keys' `ESC [ 1 ; 5 C`, are read up to their final byte and
+/// ignored.
+fn read_escape() -> Key {
+    let mut console = CONSOLE.lock();
+    let intro = console.read_byte();
+    if intro != b'[' && intro != b'O' {
+        return Key::Unknown;
+    }
+
+    let mut number = 0usize;
+    // Set once a parameter byte other than a digit of the first parameter,
+    // or an intermediate byte, has been read.
+    let mut other = false;
+    loop {
+        match console.read_byte() {
+            digit @ b'0'..=b'9' if !other => number = number.saturating_mul(10).saturating_add((digit - b'0') as usize),
+            0x20..=0x3f => other = true,
+            0x40..=0x7e if other => return Key::Unknown,
+            b'A' => return Key::Up,
+            b'B' => return Key::Down,
+            b'C' => return Key::Right,
+            b'D' => return Key::Left,
+            b'H' => return Key::Home,
+            b'F' => return Key::End,
+            b'~' => {
+                return match number {
+                    1 | 7 => Key::Home,
+                    4 | 8 => Key::End,
+                    3 => Key::Delete,
+                    _ => Key::Unknown,
+                }
+            }
+            _ => return Key::Unknown,
+        }
+    }
+}
+
+fn read_key() -> Key {
+    let byte = CONSOLE.lock().read_byte();
+    match byte {
+        b'\r' | b'\n' => Key::Enter,
+        BACKSPACE | DELETE => Key::Backspace,
+        CTRL_A => Key::Home,
+        CTRL_E => Key::End,
+        CTRL_K => Key::KillToEnd,
+        CTRL_U => Key::KillToStart,
+        CTRL_W => Key::KillWord,
+        TAB => Key::Tab,
+        ESC => read_escape(),
+        b' '..=b'~' => Key::Char(byte),
+        _ => Key::Unknown,
     }
 }
 
+/// Reads a line into `line`, after `prompt`, letting it be edited, completed
+/// and recalled from `history`, to which it's added. Rings the bell on keys
+/// that do nothing.
+fn read_line(prompt: &str, line: &mut Line, history: &mut History) {
+    *line = Line::new();
+    // How far back in `history` the line is, and the line being typed before
+    // going back.
+    let mut back = 0;
+    let mut draft = Line::new();
+    // Whether the last key was a tab that didn't complete anything, making
+    // the next one list the completions.
+    let mut tabbed = false;
+
+    loop {
+        let key = read_key();
+        if let Key::Tab = key {
+            tabbed = !complete(line, prompt, tabbed) && !tabbed;
+            continue;
+        }
+
+        tabbed = false;
+        match key {
+            Key::Char(byte) => line.insert(byte),
+            Key::Enter => break,
+            Key::Backspace if line.cursor > 0 => line.remove(line.cursor - 1, line.cursor),
+            Key::Delete if line.cursor < line.len => line.remove(line.cursor, line.cursor + 1),
+            Key::Left if line.cursor > 0 => line.move_to(line.cursor - 1),
+            Key::Right if line.cursor < line.len => line.move_to(line.cursor + 1),
+            Key::Home => line.move_to(0),
+            Key::End => line.move_to(line.len),
+            Key::KillToEnd => line.remove(line.cursor, line.len),
+            Key::KillToStart => line.remove(0, line.cursor),
+            Key::KillWord => line.remove_word(),
+            Key::Up => match history.get(back + 1) {
+                Some(previous) => {
+                    if back == 0 {
+                        draft.buf = line.buf;
+                        draft.len = line.len;
+                    }
+                    back += 1;
+                    line.replace(previous);
+                }
+                None => bell(),
+            },
+            Key::Down if back > 0 => {
+                back -= 1;
+                match history.get(back) {
+                    Some(next) => line.replace(next),
+                    None => line.replace(draft.as_bytes()),
+                }
+            }
+            _ => bell(),
+        }
+    }
+
+    cursor_right(line.len - line.cursor);
+    kprintln!();
+    history.push(line.as_bytes());
+}
+
 /// Starts a shell using `prefix` as the prefix for each line. This function
 /// returns if the `exit` command is called.
-pub fn shell(prefix: &str) -> ! {
-    unimplemented!()
+pub fn shell(prefix: &str) {
+    let mut line = Line::new();
+    let mut history = History::new();
+    loop {
+        kprint!("{}", prefix);
+        read_line(prefix, &mut line, &mut history);
+
+        let mut rest = line.as_str();
+        while !rest.is_empty() {
+            let mut words = [0u8; WORDS_LEN];
+            let mut args_buf = [""; MAX_ARGS];
+            let cmd = match Command::parse(&mut rest, &mut words, &mut args_buf) {
+                Ok(cmd) => cmd,
+                Err(Error::Empty) => continue,
+                Err(Error::TooManyArgs) => {
+                    kprintln!("error: too many arguments, at most {}", MAX_ARGS);
+                    break;
+                }
+                Err(Error::TooLong) => {
+                    kprintln!("error: command longer than {} bytes", WORDS_LEN);
+                    break;
+                }
+                Err(Error::Unterminated) => {
+                    kprintln!("error: unterminated quote or ${{");
+                    break;
+                }
+            };
+
+            match builtin(cmd.path()) {
+                Some(builtin) => match (builtin.run)(&cmd) {
+                    Flow::Continue => {}
+                    Flow::Exit => {
+                        // Send what's still buffered before the caller halts.
+                        let _ = io::Write::flush(&mut *CONSOLE.lock());
+                        return;
+                    }
+                },
+                None => kprintln!("unknown command: {}", cmd.path()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the command at the start of `*line` and checks its arguments.
+    fn assert_parses(line: &mut &str, expected: &[&str]) {
+        let (mut words, mut buf) = ([0u8; WORDS_LEN], [""; MAX_ARGS]);
+        let cmd = Command::parse(line, &mut words, &mut buf).expect("parses");
+        assert_eq!(cmd.args.as_slice(), expected);
+    }
+
+    /// Parses the command at the start of `*line`, which must fail.
+    fn parse_err(line: &mut &str) -> Error {
+        let (mut words, mut buf) = ([0u8; WORDS_LEN], [""; MAX_ARGS]);
+        match Command::parse(line, &mut words, &mut buf) {
+            Ok(cmd) => panic!("parsed {:?}", cmd.args.as_slice()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parse_unterminated_quotes() {
+        assert_eq!(parse_err(&mut "echo 'a b"), Error::Unterminated);
+        assert_eq!(parse_err(&mut "echo \"a b"), Error::Unterminated);
+        assert_eq!(parse_err(&mut "echo \"a; echo b"), Error::Unterminated);
+        assert_eq!(parse_err(&mut "echo \"a\\\""), Error::Unterminated);
+    }
+
+    #[test]
+    fn parse_escaped_dollar() {
+        assert_parses(&mut r#"echo \$HOME "\$HOME" '$HOME' \${"#, &["echo", "$HOME", "$HOME", "$HOME", "${"]);
+    }
+
+    #[test]
+    fn parse_undefined_variable() {
+        // unquoted, it expands to no argument at all; quoted, to an empty one
+        let mut line = "echo a$NOT_SET b $NOT_SET ${NOT_SET} \"$NOT_SET\"";
+        assert_parses(&mut line, &["echo", "a", "b", ""]);
+        assert_parses(&mut "$NOT_SET echo", &["echo"]);
+        assert_eq!(parse_err(&mut "$NOT_SET ${NOT_SET}"), Error::Empty);
+    }
+
+    #[test]
+    fn parse_unterminated_brace() {
+        assert_eq!(parse_err(&mut "echo ${HOME"), Error::Unterminated);
+        assert_eq!(parse_err(&mut "echo ${"), Error::Unterminated);
+        assert_eq!(parse_err(&mut "echo ${HOME; echo b}"), Error::Unterminated);
+        assert_eq!(parse_err(&mut "echo \"${HOME\""), Error::Unterminated);
+    }
+
+    #[test]
+    fn parse_empty_commands() {
+        let mut line = "echo a;; ;echo b;";
+        assert_parses(&mut line, &["echo", "a"]);
+        assert_eq!(parse_err(&mut line), Error::Empty);
+        assert_eq!(parse_err(&mut line), Error::Empty);
+        assert_parses(&mut line, &["echo", "b"]);
+        assert_eq!(line, "");
+        assert_eq!(parse_err(&mut " \t"), Error::Empty);
+    }
+
+    #[test]
+    fn parse_overflow() {
+        let mut line = [b' '; 2 * (MAX_ARGS + 1)];
+        line.iter_mut().step_by(2).for_each(|b| *b = b'a');
+        let line = core::str::from_utf8(&line).unwrap();
+        assert_eq!(parse_err(&mut &line[..2 * MAX_ARGS + 1]), Error::TooManyArgs);
+
+        let (mut words, mut buf) = ([0u8; WORDS_LEN], [""; MAX_ARGS]);
+        let cmd = Command::parse(&mut &line[..2 * MAX_ARGS], &mut words, &mut buf).expect("parses");
+        assert_eq!(cmd.args.len(), MAX_ARGS);
+
+        let (mut words, mut buf) = ([0u8; WORDS_LEN], [""; 2]);
+        let e = Command::parse(&mut "echo a b", &mut words, &mut buf).err();
+        assert_eq!(e, Some(Error::TooManyArgs));
+
+        let (mut words, mut buf) = ([0u8; 8], [""; MAX_ARGS]);
+        let e = Command::parse(&mut "echo abcde", &mut words, &mut buf).err();
+        assert_eq!(e, Some(Error::TooLong));
+    }
 }
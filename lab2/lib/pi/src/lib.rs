@@ -7,5 +7,6 @@
 
 pub mod common;
 pub mod gpio;
+pub mod sd;
 pub mod timer;
 pub mod uart;
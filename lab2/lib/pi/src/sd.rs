@@ -0,0 +1,283 @@
+use core::time::Duration;
+
+use shim::io;
+use shim::ioerr;
+
+use volatile::prelude::*;
+use volatile::{Volatile, ReadVolatile};
+
+use crate::timer;
+use crate::common::IO_BASE;
+
+/// The base address of the `EMMC2` registers, the SD host controller wired
+/// to the Pi 4's SD card slot.
+const EMMC2_REG_BASE: usize = IO_BASE + 0x340000;
+
+/// The size in bytes of a sector.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Base clock of the controller if its capabilities don't report one.
+const DEFAULT_BASE_CLOCK_HZ: u32 = 100_000_000;
+
+/// Clock rates while identifying the card and while transferring data.
+const IDENTIFICATION_CLOCK_HZ: u32 = 400_000;
+const TRANSFER_CLOCK_HZ: u32 = 25_000_000;
+
+/// How long the controller and the card may take to finish an operation.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// `STATUS` bits.
+const SR_CMD_INHIBIT: u32 = 1 << 0;
+const SR_DAT_INHIBIT: u32 = 1 << 1;
+
+// `CONTROL0` bits: bus power on at 3.3V.
+const C0_POWER_3V3: u32 = 0b1111 << 8;
+
+// `CONTROL1` bits.
+const C1_CLK_INTLEN: u32 = 1 << 0;
+const C1_CLK_STABLE: u32 = 1 << 1;
+const C1_CLK_EN: u32 = 1 << 2;
+const C1_CLK_FREQ_MASK: u32 = 0x3ff << 6;
+const C1_TOUNIT_MAX: u32 = 0xe << 16;
+const C1_SRST_HC: u32 = 1 << 24;
+const C1_SRST_CMD: u32 = 1 << 25;
+const C1_SRST_DATA: u32 = 1 << 26;
+
+// `INTERRUPT` bits.
+const INT_CMD_DONE: u32 = 1 << 0;
+const INT_DATA_DONE: u32 = 1 << 1;
+const INT_READ_RDY: u32 = 1 << 5;
+const INT_ERROR: u32 = 0xffff << 16 | 1 << 15;
+const INT_TIMEOUT: u32 = 1 << 16 | 1 << 20;
+
+// `CMDTM` fields.
+const RESPONSE_136: u32 = 1 << 16;
+const RESPONSE_48: u32 = 2 << 16;
+const RESPONSE_48_BUSY: u32 = 3 << 16;
+const CRC_CHECK: u32 = 1 << 19;
+const INDEX_CHECK: u32 = 1 << 20;
+const DATA_READ: u32 = 1 << 21 | 1 << 4;
+
+const fn command(index: u32, flags: u32) -> u32 {
+    index << 24 | flags
+}
+
+const GO_IDLE_STATE: u32 = command(0, 0);
+const ALL_SEND_CID: u32 = command(2, RESPONSE_136 | CRC_CHECK);
+const SEND_RELATIVE_ADDR: u32 = command(3, RESPONSE_48 | CRC_CHECK | INDEX_CHECK);
+const SELECT_CARD: u32 = command(7, RESPONSE_48_BUSY | CRC_CHECK | INDEX_CHECK);
+const SEND_IF_COND: u32 = command(8, RESPONSE_48 | CRC_CHECK | INDEX_CHECK);
+const SET_BLOCKLEN: u32 = command(16, RESPONSE_48 | CRC_CHECK | INDEX_CHECK);
+const READ_SINGLE_BLOCK: u32 = command(17, RESPONSE_48 | CRC_CHECK | INDEX_CHECK | DATA_READ);
+const SD_SEND_OP_COND: u32 = command(41, RESPONSE_48);
+const APP_CMD: u32 = command(55, RESPONSE_48 | CRC_CHECK | INDEX_CHECK);
+
+/// `SEND_IF_COND` argument: 2.7-3.6V and a check pattern the card echoes.
+const IF_COND: u32 = 0x1aa;
+
+// Operation conditions register bits.
+const OCR_VOLTAGES: u32 = 0x00ff8000;
+const OCR_HCS: u32 = 1 << 30;
+const OCR_POWERED_UP: u32 = 1 << 31;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct Registers {
+    ARG2: Volatile<u32>,
+    BLKSIZECNT: Volatile<u32>,
+    ARG1: Volatile<u32>,
+    CMDTM: Volatile<u32>,
+    RESP: [ReadVolatile<u32>; 4],
+    DATA: ReadVolatile<u32>,
+    STATUS: ReadVolatile<u32>,
+    CONTROL0: Volatile<u32>,
+    CONTROL1: Volatile<u32>,
+    INTERRUPT: Volatile<u32>,
+    IRPT_MASK: Volatile<u32>,
+    IRPT_EN: Volatile<u32>,
+    CONTROL2: Volatile<u32>,
+    CAPABILITIES: ReadVolatile<u32>,
+}
+
+/// The SD card, read one sector at a time by polling the `EMMC2` controller.
+pub struct Sd {
+    registers: &'static mut Registers,
+    /// The card's relative address, in the upper 16 bits.
+    rca: u32,
+    /// Whether the card is addressed by sector rather than by byte.
+    high_capacity: bool,
+}
+
+impl Sd {
+    /// Resets the SD host controller and initializes the card in the slot:
+    /// the card is identified at 400kHz, then selected for data transfers
+    /// at 25MHz on a 1-bit bus.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `TimedOut` if there's no card or the card
+    /// doesn't answer, and an error of another kind if the card can't be
+    /// used.
+    pub fn new() -> io::Result<Sd> {
+        let registers = unsafe { &mut *(EMMC2_REG_BASE as *mut Registers) };
+        let mut sd = Sd { registers, rca: 0, high_capacity: false };
+
+        sd.reset()?;
+        sd.set_clock(IDENTIFICATION_CLOCK_HZ)?;
+        sd.command(GO_IDLE_STATE, 0)?;
+
+        // Version 2 cards echo the check pattern; version 1 cards don't answer.
+        let version_2 = match sd.command(SEND_IF_COND, IF_COND) {
+            Ok(response) if response & 0xfff == IF_COND => true,
+            Ok(_) => return ioerr!(InvalidData, "SD card doesn't support 3.3V"),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => false,
+            Err(e) => return Err(e),
+        };
+
+        let hcs = if version_2 { OCR_HCS } else { 0 };
+        let deadline = timer::current_time() + TIMEOUT;
+        let ocr = loop {
+            let ocr = sd.app_command(SD_SEND_OP_COND, hcs | OCR_VOLTAGES)?;
+            if ocr & OCR_POWERED_UP != 0 {
+                break ocr;
+            } else if timer::current_time() > deadline {
+                return ioerr!(TimedOut, "SD card didn't power up");
+            }
+            timer::spin_sleep(Duration::from_millis(10));
+        };
+
+        sd.high_capacity = ocr & OCR_HCS != 0;
+        sd.command(ALL_SEND_CID, 0)?;
+        sd.rca = sd.command(SEND_RELATIVE_ADDR, 0)? & 0xffff0000;
+        sd.set_clock(TRANSFER_CLOCK_HZ)?;
+        sd.command(SELECT_CARD, sd.rca)?;
+        if !sd.high_capacity {
+            sd.command(SET_BLOCKLEN, SECTOR_SIZE as u32)?;
+        }
+
+        Ok(sd)
+    }
+
+    /// Reads the sector `n` into the first `SECTOR_SIZE` bytes of `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `buf` is shorter than a
+    /// sector or `n` can't be addressed, and an error if the read fails.
+    pub fn read_sector(&mut self, n: u32, buf: &mut [u8]) -> io::Result<()> {
+        if buf.len() < SECTOR_SIZE {
+            return ioerr!(InvalidInput, "buffer shorter than a sector");
+        }
+
+        let address = if self.high_capacity {
+            n
+        } else {
+            match n.checked_mul(SECTOR_SIZE as u32) {
+                Some(address) => address,
+                None => return ioerr!(InvalidInput, "sector out of range"),
+            }
+        };
+
+        self.wait_for(|r| !r.STATUS.has_mask(SR_DAT_INHIBIT))?;
+        self.registers.BLKSIZECNT.write(1 << 16 | SECTOR_SIZE as u32);
+        self.command(READ_SINGLE_BLOCK, address)?;
+        self.wait_interrupt(INT_READ_RDY)?;
+        for word in buf[..SECTOR_SIZE].chunks_mut(4) {
+            word.copy_from_slice(&self.registers.DATA.read().to_le_bytes());
+        }
+
+        self.wait_interrupt(INT_DATA_DONE)
+    }
+
+    /// Resets the controller and powers the card.
+    fn reset(&mut self) -> io::Result<()> {
+        self.registers.CONTROL0.write(0);
+        self.registers.CONTROL1.or_mask(C1_SRST_HC);
+        self.wait_for(|r| !r.CONTROL1.has_mask(C1_SRST_HC))?;
+
+        self.registers.CONTROL0.write(C0_POWER_3V3);
+        self.registers.CONTROL1.or_mask(C1_CLK_INTLEN | C1_TOUNIT_MAX);
+        timer::spin_sleep(Duration::from_millis(10));
+
+        // Interrupts are polled for, never signalled.
+        self.registers.IRPT_EN.write(0);
+        self.registers.IRPT_MASK.write(0xffffffff);
+        Ok(())
+    }
+
+    /// Sets the card's clock to at most `hz`.
+    fn set_clock(&mut self, hz: u32) -> io::Result<()> {
+        self.wait_for(|r| r.STATUS.read() & (SR_CMD_INHIBIT | SR_DAT_INHIBIT) == 0)?;
+        self.registers.CONTROL1.and_mask(!C1_CLK_EN);
+        timer::spin_sleep(Duration::from_micros(10));
+
+        // The card's clock is the base clock divided by twice the 10-bit
+        // divisor, or the base clock if the divisor is 0.
+        let base = match (self.registers.CAPABILITIES.read() >> 8) & 0xff {
+            0 => DEFAULT_BASE_CLOCK_HZ,
+            mhz => mhz * 1_000_000,
+        };
+        let divisor = core::cmp::min((base + 2 * hz - 1) / (2 * hz), 0x3ff);
+        let freq = (divisor & 0xff) << 8 | (divisor >> 8) << 6;
+        let control1 = self.registers.CONTROL1.read();
+        self.registers.CONTROL1.write(control1 & !C1_CLK_FREQ_MASK | freq);
+        self.wait_for(|r| r.CONTROL1.has_mask(C1_CLK_STABLE))?;
+
+        self.registers.CONTROL1.or_mask(C1_CLK_EN);
+        timer::spin_sleep(Duration::from_micros(10));
+        Ok(())
+    }
+
+    /// Sends the command `cmd` with the argument `arg`. Returns the first
+    /// word of the card's response.
+    fn command(&mut self, cmd: u32, arg: u32) -> io::Result<u32> {
+        self.wait_for(|r| !r.STATUS.has_mask(SR_CMD_INHIBIT))?;
+        let pending = self.registers.INTERRUPT.read();
+        self.registers.INTERRUPT.write(pending);
+        self.registers.ARG1.write(arg);
+        self.registers.CMDTM.write(cmd);
+        self.wait_interrupt(INT_CMD_DONE)?;
+        Ok(self.registers.RESP[0].read())
+    }
+
+    /// Sends the application specific command `cmd` with the argument `arg`.
+    fn app_command(&mut self, cmd: u32, arg: u32) -> io::Result<u32> {
+        self.command(APP_CMD, self.rca)?;
+        self.command(cmd, arg)
+    }
+
+    /// Waits for the interrupts `mask` and acknowledges them. If an error
+    /// interrupt is raised instead, the command and data lines are reset.
+    fn wait_interrupt(&mut self, mask: u32) -> io::Result<()> {
+        let deadline = timer::current_time() + TIMEOUT;
+        loop {
+            let pending = self.registers.INTERRUPT.read();
+            if pending & INT_ERROR != 0 {
+                self.registers.INTERRUPT.write(pending);
+                self.registers.CONTROL1.or_mask(C1_SRST_CMD | C1_SRST_DATA);
+                self.wait_for(|r| r.CONTROL1.read() & (C1_SRST_CMD | C1_SRST_DATA) == 0)?;
+                if pending & INT_TIMEOUT != 0 {
+                    return ioerr!(TimedOut, "SD card didn't answer");
+                }
+                return ioerr!(Other, "SD command failed");
+            } else if pending & mask == mask {
+                self.registers.INTERRUPT.write(mask);
+                return Ok(());
+            } else if timer::current_time() > deadline {
+                return ioerr!(TimedOut, "SD controller timed out");
+            }
+        }
+    }
+
+    /// Waits until `ready` returns `true` for the registers.
+    fn wait_for<F: Fn(&Registers) -> bool>(&self, ready: F) -> io::Result<()> {
+        let deadline = timer::current_time() + TIMEOUT;
+        while !ready(&*self.registers) {
+            if timer::current_time() > deadline {
+                return ioerr!(TimedOut, "SD controller timed out");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -122,6 +122,13 @@ impl MiniUart {
         self.registers.IO.write(byte);
     }
 
+    /// Returns `true` if the output FIFO has space for at least one byte. If
+    /// this method returns `true`, a subsequent call to `write_byte` is
+    /// guaranteed to return immediately. This method does not block.
+    pub fn can_write(&self) -> bool {
+        self.registers.LSR.has_mask(0b1 << 5)
+    }
+
     /// Returns `true` if there is at least one byte ready to be read. If this
     /// method returns `true`, a subsequent call to `read_byte` is guaranteed to
     /// return immediately. This method does not block.
@@ -139,14 +146,13 @@ impl MiniUart {
     /// return immediately.
     pub fn wait_for_byte(&self) -> Result<(), ()> {
         if let Some(t) = self.timeout {
-            if self.has_byte() {
-                return Ok(());
-            }
-            timer::spin_sleep(t);
-            if self.has_byte() {
-                return Ok(());
-            } else {
-                return Err(());
+            let deadline = timer::current_time() + t;
+            loop {
+                if self.has_byte() {
+                    return Ok(());
+                } else if timer::current_time() >= deadline {
+                    return Err(());
+                }
             }
         } else {
             loop {
@@ -0,0 +1,291 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use serial::core::SerialDevice;
+use serial::SystemPort;
+use termios::Termios;
+use xmodem::{Mode, PacketSize, Progress, Xmodem, XmodemConfig};
+
+use crate::{draw_progress, now};
+
+/// Key starting a local command: Ctrl-]. Pressing it twice sends it.
+const ESCAPE: u8 = 0x1d;
+
+/// How long a read of the TTY waits for data before the keyboard is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const HELP: &str = "--- Ctrl-] followed by: q quit, s send a file with XMODEM, h toggle hex view, \
+                    b send break, ? help\r\n";
+
+/// Puts the terminal on stdin into raw mode until dropped.
+struct RawMode {
+    fd: i32,
+    saved: Termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let fd = io::stdin().as_raw_fd();
+        let saved = Termios::from_fd(fd)?;
+        let mut raw = saved;
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, termios::TCSANOW, &raw)?;
+        Ok(RawMode { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, termios::TCSANOW, &self.saved);
+    }
+}
+
+/// A log of the bytes received from the TTY, each line prefixed with the time
+/// since the session started.
+struct Log {
+    file: BufWriter<File>,
+    start: Instant,
+    line_start: bool,
+}
+
+impl Log {
+    fn create(path: &Path) -> io::Result<Log> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Log { file, start: Instant::now(), line_start: true })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for &byte in bytes {
+            if self.line_start {
+                let time = self.start.elapsed();
+                write!(self.file, "[{:5}.{:03}] ", time.as_secs(), time.subsec_millis())?;
+                self.line_start = false;
+            }
+
+            self.file.write_all(&[byte])?;
+            self.line_start = byte == b'\n';
+        }
+
+        self.file.flush()
+    }
+}
+
+/// Settings of a console session.
+pub struct Settings {
+    /// File to log received bytes to.
+    pub log: Option<PathBuf>,
+    /// Timeout of the TTY while sending a file.
+    pub timeout: Duration,
+    /// Policy for files sent with XMODEM.
+    pub config: XmodemConfig,
+    /// Size of the packets of files sent with XMODEM.
+    pub size: PacketSize,
+}
+
+/// An interactive session relaying bytes between the terminal and a TTY.
+struct Console<'a> {
+    port: &'a mut SystemPort,
+    keys: Receiver<u8>,
+    settings: &'a Settings,
+    log: Option<Log>,
+    hex: bool,
+}
+
+/// Progress callback for files sent from the console, which is in raw mode.
+fn progress_fn(progress: Progress) {
+    if let Progress::Transferred(stats) = progress {
+        draw_progress(&stats);
+    }
+}
+
+/// Prints a local message, which starts with `---` to set it apart from the
+/// TTY's output.
+macro_rules! note {
+    ($($arg:tt)*) => ({
+        print!("\r\n--- ");
+        print!($($arg)*);
+        print!("\r\n");
+        io::stdout().flush()
+    })
+}
+
+/// Runs an interactive console on `port` until the user quits or stdin ends.
+///
+/// The terminal is put into raw mode, so every key is sent to the TTY as it
+/// is typed, except for the escape key Ctrl-] which starts a local command.
+pub fn run(port: &mut SystemPort, settings: &Settings) -> io::Result<()> {
+    let log = match settings.log {
+        Some(ref path) => Some(Log::create(path)?),
+        None => None,
+    };
+
+    // Reading stdin blocks, so it's done on a thread of its own.
+    let (sender, keys) = channel();
+    std::thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => continue,
+                _ => break,
+            }
+        }
+    });
+
+    port.set_timeout(POLL_INTERVAL)?;
+    let _raw = RawMode::enable()?;
+    print!("{}", HELP);
+    io::stdout().flush()?;
+
+    let mut console = Console { port, keys, settings, log, hex: false };
+    let result = console.relay();
+    console.port.set_timeout(settings.timeout)?;
+    result
+}
+
+impl<'a> Console<'a> {
+    /// Relays bytes both ways until the user quits or stdin ends.
+    fn relay(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 256];
+        let mut escaped = false;
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(n) => self.show(&buf[..n])?,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+
+            loop {
+                let key = match self.keys.try_recv() {
+                    Ok(key) => key,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                };
+
+                if !escaped && key == ESCAPE {
+                    escaped = true;
+                } else if !escaped || key == ESCAPE {
+                    escaped = false;
+                    self.port.write_all(&[key])?;
+                } else {
+                    escaped = false;
+                    if !self.command(key)? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shows and logs `bytes` received from the TTY.
+    fn show(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(ref mut log) = self.log {
+            log.write(bytes)?;
+        }
+
+        let mut stdout = io::stdout();
+        if self.hex {
+            for byte in bytes {
+                write!(stdout, "{:02x} ", byte)?;
+            }
+        } else {
+            stdout.write_all(bytes)?;
+        }
+        stdout.flush()
+    }
+
+    /// Runs the local command for `key`. Returns `false` if the console
+    /// should quit.
+    fn command(&mut self, key: u8) -> io::Result<bool> {
+        match key {
+            b'q' | b'Q' | b'.' => return Ok(false),
+            b's' | b'S' => self.send_file()?,
+            b'h' | b'H' => {
+                self.hex = !self.hex;
+                note!("hex view {}", if self.hex { "on" } else { "off" })?;
+            }
+            b'b' | b'B' => {
+                termios::tcsendbreak(self.port.as_raw_fd(), 0)?;
+                note!("sent break")?;
+            }
+            _ => {
+                print!("\r\n{}", HELP);
+                io::stdout().flush()?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Prompts for a line of input, echoing it locally. Returns `None` if the
+    /// prompt is cancelled with Ctrl-C, Ctrl-] or an empty line.
+    fn prompt(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        print!("\r\n--- {}", prompt);
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        loop {
+            let key = match self.keys.recv() {
+                Ok(key) => key,
+                Err(_) => return Ok(None),
+            };
+
+            match key {
+                b'\r' | b'\n' => break,
+                0x03 | ESCAPE => {
+                    line.clear();
+                    break;
+                }
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        print!("\x08 \x08");
+                    }
+                }
+                key if key >= b' ' => {
+                    line.push(key as char);
+                    print!("{}", key as char);
+                }
+                _ => continue,
+            }
+            io::stdout().flush()?;
+        }
+
+        print!("\r\n");
+        io::stdout().flush()?;
+        Ok(if line.is_empty() { None } else { Some(line) })
+    }
+
+    /// Asks for a path and sends the file there with XMODEM.
+    fn send_file(&mut self) -> io::Result<()> {
+        let path = match self.prompt("send file: ")? {
+            Some(path) => path,
+            None => return note!("cancelled"),
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => return note!("{}: {}", path, e),
+        };
+
+        let total = file.metadata()?.len() as usize;
+        self.port.set_timeout(self.settings.timeout)?;
+        let result = {
+            let mut transmitter = Xmodem::new_with_progress(&mut *self.port, progress_fn);
+            transmitter.set_mode(Mode::Crc);
+            transmitter.set_clock(now);
+            transmitter.set_config(self.settings.config);
+            transmitter.set_total(Some(total));
+            transmitter.send_data(BufReader::new(file), self.settings.size)
+        };
+        self.port.set_timeout(POLL_INTERVAL)?;
+
+        // Keys typed during the transfer were not meant for the TTY.
+        while self.keys.try_recv().is_ok() {}
+        match result {
+            Ok(n) => note!("sent {} bytes of {}", n, path),
+            Err(e) => note!("sending {} failed: {}", path, e),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use xmodem::{BINARY_START_ADDR, MAX_BINARY_SIZE};
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+
+/// Length of the part of an ELF64 program header read here.
+const PHDR_LEN: usize = 40;
+
+/// Returns an `InvalidData` error with the message `msg`.
+fn invalid<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid ELF: {}", msg)))
+}
+
+fn u16_at(buf: &[u8], at: usize) -> io::Result<u16> {
+    match buf.get(at..at + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => invalid("truncated"),
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> io::Result<u32> {
+    Ok(u16_at(buf, at)? as u32 | (u16_at(buf, at + 2)? as u32) << 16)
+}
+
+fn u64_at(buf: &[u8], at: usize) -> io::Result<u64> {
+    Ok(u32_at(buf, at)? as u64 | (u32_at(buf, at + 4)? as u64) << 32)
+}
+
+/// A flat binary extracted from an ELF file.
+pub struct Image {
+    /// The binary, starting at `BINARY_START_ADDR`.
+    pub data: Vec<u8>,
+    /// The entry point.
+    pub entry: u64,
+}
+
+/// Returns `true` if `file` starts with the ELF magic. The file is rewound.
+pub fn is_elf(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    let is_elf = match file.read_exact(&mut magic) {
+        Ok(()) => &magic == MAGIC,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(is_elf)
+}
+
+/// Extracts the flat binary the bootloader expects from the ELF64 AArch64
+/// file `elf`, like `objcopy -O binary`: the file contents of its `PT_LOAD`
+/// segments, placed at their physical addresses relative to
+/// `BINARY_START_ADDR`, with gaps between them zeroed.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if `elf` isn't a little-endian
+/// ELF64 AArch64 file, if a segment lies below `BINARY_START_ADDR` or outside
+/// of the file, or if the binary is larger than `MAX_BINARY_SIZE`.
+pub fn extract(elf: &[u8]) -> io::Result<Image> {
+    if elf.len() < 64 || &elf[..4] != MAGIC {
+        return invalid("bad magic");
+    } else if elf[4] != ELFCLASS64 || elf[5] != ELFDATA2LSB {
+        return invalid("not a little-endian 64-bit file");
+    } else if u16_at(elf, 18)? != EM_AARCH64 {
+        return invalid("not an AArch64 file");
+    }
+
+    let entry = u64_at(elf, 24)?;
+    let phoff = u64_at(elf, 32)? as usize;
+    let phentsize = u16_at(elf, 54)? as usize;
+    let phnum = u16_at(elf, 56)? as usize;
+
+    let mut data = vec![];
+    for i in 0..phnum {
+        let ph = i.checked_mul(phentsize).and_then(|at| at.checked_add(phoff));
+        let ph = match ph.and_then(|ph| elf.get(ph..)).and_then(|rest| rest.get(..PHDR_LEN)) {
+            Some(ph) => ph,
+            None => return invalid("program header outside of the file"),
+        };
+        let (offset, paddr, filesz) = (u64_at(ph, 8)? as usize, u64_at(ph, 24)?, u64_at(ph, 32)? as usize);
+        if u32_at(ph, 0)? != PT_LOAD || filesz == 0 {
+            continue;
+        } else if paddr < BINARY_START_ADDR as u64 {
+            return invalid("segment below the load address");
+        }
+
+        let start = (paddr - BINARY_START_ADDR as u64) as usize;
+        let end = match start.checked_add(filesz) {
+            Some(end) if end <= MAX_BINARY_SIZE => end,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "binary larger than the bootloader's maximum of {} bytes", MAX_BINARY_SIZE))),
+        };
+
+        let contents = match offset.checked_add(filesz).and_then(|file_end| elf.get(offset..file_end)) {
+            Some(contents) => contents,
+            None => return invalid("segment outside of the file"),
+        };
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(contents);
+    }
+
+    Ok(Image { data, entry })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offset of the program header in files made by `elf()`.
+    const PHOFF: usize = 64;
+
+    /// Returns an ELF64 AArch64 file with one `PT_LOAD` segment holding `data`
+    /// at `paddr`, which is also its entry point.
+    fn elf(paddr: u64, data: &[u8]) -> Vec<u8> {
+        let mut elf = vec![0u8; PHOFF + 56];
+        elf[..4].copy_from_slice(MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[18..20].copy_from_slice(&EM_AARCH64.to_le_bytes());
+        elf[24..32].copy_from_slice(&paddr.to_le_bytes());
+        elf[32..40].copy_from_slice(&(PHOFF as u64).to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+        let ph = PHOFF;
+        elf[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        elf[ph + 8..ph + 16].copy_from_slice(&((PHOFF + 56) as u64).to_le_bytes());
+        elf[ph + 24..ph + 32].copy_from_slice(&paddr.to_le_bytes());
+        elf[ph + 32..ph + 40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        elf.extend_from_slice(data);
+        elf
+    }
+
+    fn assert_invalid(elf: &[u8]) {
+        match extract(elf) {
+            Ok(_) => panic!("extracted an invalid ELF file"),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+        }
+    }
+
+    #[test]
+    fn extract_minimal() {
+        let start = BINARY_START_ADDR as u64;
+        let image = extract(&elf(start + 4, b"kernel")).expect("valid ELF");
+        assert_eq!(image.data, b"\0\0\0\0kernel");
+        assert_eq!(image.entry, start + 4);
+
+        // segments that aren't loaded are skipped
+        let mut file = elf(0, b"notes");
+        file[PHOFF] = 4;
+        assert!(extract(&file).expect("valid ELF").data.is_empty());
+    }
+
+    #[test]
+    fn extract_truncated() {
+        let file = elf(BINARY_START_ADDR as u64, b"kernel");
+        assert_invalid(&file[..63]);
+        assert_invalid(&file[..PHOFF + 39]);
+        assert_invalid(&file[..file.len() - 1]);
+    }
+
+    #[test]
+    fn extract_out_of_range() {
+        let file = elf(BINARY_START_ADDR as u64, b"kernel");
+        let set = |at: usize, value: u64| {
+            let mut file = file.clone();
+            file[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            file
+        };
+
+        // program headers past the end of the file, or of the address space
+        assert_invalid(&set(32, file.len() as u64));
+        assert_invalid(&set(32, u64::MAX));
+        let mut file_many = set(32, u64::MAX - 56);
+        file_many[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_invalid(&file_many);
+
+        // segments past the end of the file, or of the address space
+        assert_invalid(&set(PHOFF + 8, file.len() as u64));
+        assert_invalid(&set(PHOFF + 8, u64::MAX));
+        assert_invalid(&set(PHOFF + 32, u64::MAX));
+
+        // segments outside of the space the bootloader loads to
+        assert_invalid(&set(PHOFF + 24, BINARY_START_ADDR as u64 - 1));
+        assert_invalid(&set(PHOFF + 24, (BINARY_START_ADDR + MAX_BINARY_SIZE) as u64));
+        assert_invalid(&set(PHOFF + 24, u64::MAX));
+    }
+
+    #[test]
+    fn is_elf_rewinds() {
+        let path = std::env::temp_dir().join(format!("ttywrite-elf-test-{}", std::process::id()));
+        for (contents, expected) in &[(elf(0, b"x"), true), (b"\x7fEL".to_vec(), false), (vec![0; 64], false)] {
+            std::fs::write(&path, contents).expect("write test file");
+            let mut file = File::open(&path).expect("open test file");
+            assert_eq!(is_elf(&mut file).expect("read test file"), *expected);
+            let mut read = vec![];
+            file.read_to_end(&mut read).expect("read test file");
+            assert_eq!(&read, contents);
+        }
+        std::fs::remove_file(&path).expect("remove test file");
+    }
+}
@@ -1,14 +1,20 @@
+mod console;
+mod elf;
 mod parsers;
 
 use serial;
 use structopt;
 use structopt_derive::StructOpt;
-use xmodem::Xmodem;
+use xmodem::{Xmodem, Ymodem, Zmodem, Header};
 
-use xmodem::{Progress};
+use xmodem::{ImageHeader, Mode, PacketSize, Progress, ProgressFn, Resume, TransferStats, XmodemConfig};
+use xmodem::{BINARY_START_ADDR, IMAGE_FLAG_LZ4, IMAGE_HEADER_LEN, MAX_BINARY_SIZE};
 
-use std::path::PathBuf;
-use std::time::Duration;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, Read, Seek, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use structopt::StructOpt;
 use serial::core::{CharSize, BaudRate, StopBits, FlowControl, SerialDevice, SerialPortSettings};
@@ -18,8 +24,9 @@ use parsers::{parse_width, parse_stop_bits, parse_flow_control, parse_baud_rate}
 #[derive(StructOpt, Debug)]
 #[structopt(about = "Write to TTY using the XMODEM protocol by default.")]
 struct Opt {
-    #[structopt(short = "i", help = "Input file (defaults to stdin if not set)", parse(from_os_str))]
-    input: Option<PathBuf>,
+    #[structopt(short = "i", help = "Input file (defaults to stdin if not set); repeat with YMODEM to send several files",
+                number_of_values_raw = "1", parse(from_os_str))]
+    input: Vec<PathBuf>,
 
     #[structopt(short = "b", long = "baud", parse(try_from_str = "parse_baud_rate"),
                 help = "Set baud rate", default_value = "115200")]
@@ -46,17 +53,271 @@ struct Opt {
 
     #[structopt(short = "r", long = "raw", help = "Disable XMODEM")]
     raw: bool,
+
+    #[structopt(short = "k", long = "1k", help = "Send 1024-byte XMODEM-1K packets")]
+    one_k: bool,
+
+    #[structopt(short = "y", long = "ymodem", help = "Use YMODEM batch transfer with file name and size")]
+    ymodem: bool,
+
+    #[structopt(short = "z", long = "zmodem", help = "Use ZMODEM streaming transfer with file name and size")]
+    zmodem: bool,
+
+    #[structopt(short = "R", long = "resume", parse(try_from_str),
+                help = "Resume an interrupted XMODEM transfer up to N times; the other side must resume too, as the bootloader and --receive do",
+                default_value = "0")]
+    resume: u32,
+
+    #[structopt(long = "restart", parse(try_from_str),
+                help = "Restart a failed XMODEM transfer of an input file from the start up to N times, e.g. after a board reset",
+                default_value = "0")]
+    restart: u32,
+
+    #[structopt(short = "n", long = "retries", parse(try_from_str),
+                help = "Set number of retries per packet", default_value = "9")]
+    retries: usize,
+
+    #[structopt(long = "receive", help = "Receive from the TTY instead of sending")]
+    receive: bool,
+
+    #[structopt(short = "o", help = "Output file or directory for received files (defaults to stdout if not set); implies --receive",
+                parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    #[structopt(short = "I", long = "image", help = "Frame input files as boot images for the bootloader; implied for ELF files")]
+    image: bool,
+
+    #[structopt(short = "C", long = "compress", help = "Compress boot images with LZ4 for the bootloader to decompress; implies -I")]
+    compress: bool,
+
+    #[structopt(short = "c", long = "console", help = "Open an interactive console on the TTY, after sending any input files")]
+    console: bool,
+
+    #[structopt(short = "l", long = "log", help = "Log the console session to a file with timestamps",
+                parse(from_os_str))]
+    log: Option<PathBuf>,
 }
 
+/// Width of the progress bar in characters.
+const BAR_WIDTH: usize = 40;
+
 fn progress_fn(progress: Progress) {
-    println!("Progress: {:?}", progress);
+    match progress {
+        Progress::Waiting => println!("Waiting for receiver..."),
+        Progress::Started => println!("Started"),
+        Progress::Transferred(stats) => draw_progress(&stats),
+        _ => {}
+    }
 }
 
-fn main() {
-    use std::fs::File;
-    use std::io::{self, BufReader};
+/// Progress callback used when received data is written to stdout.
+fn quiet_fn(_: Progress) {}
+
+/// Redraws the progress bar for `stats` on the current line.
+fn draw_progress(stats: &TransferStats) {
+    let rate = match stats.throughput() {
+        Some(rate) => format!(", {:.1} KiB/s", rate as f64 / 1024.0),
+        None => String::new(),
+    };
+
+    match stats.total {
+        Some(total) if total > 0 => {
+            let done = std::cmp::min(stats.bytes, total);
+            let filled = done * BAR_WIDTH / total;
+            print!("\r[{}{}] {:3}% {}/{} bytes{}, {} retries",
+                   "#".repeat(filled), " ".repeat(BAR_WIDTH - filled),
+                   done * 100 / total, done, total, rate, stats.retries);
+        }
+        _ => print!("\r{} bytes{}, {} retries", stats.bytes, rate, stats.retries),
+    }
+
+    std::io::stdout().flush().expect("failed to flush stdout");
+}
+
+/// Clock for transfer statistics.
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Returns a YMODEM header for `len` bytes sent from the file at `path` using
+/// its name and modification time.
+fn file_header(path: &Path, len: usize) -> Header {
+    let name = path.file_name().and_then(|n| n.to_str()).expect("file name must be valid UTF-8");
+    let metadata = std::fs::metadata(path).expect("failed to read file metadata");
+    let mtime = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    Header::new(name, Some(len), mtime).expect("invalid YMODEM file name")
+}
+
+/// Data sent from an input file.
+trait Input: Read + Seek {}
+
+impl<T: Read + Seek> Input for T {}
+
+/// Opens the input file at `path`. An ELF kernel is converted to the flat
+/// binary the bootloader expects and framed as a boot image, as is any file if
+/// `image` or `compress` is set. Boot images are compressed if `compress` is
+/// set. Returns the data to send and its length.
+fn open_input(path: &Path, image: bool, compress: bool) -> (Box<dyn Input>, usize) {
+    let mut file = File::open(path).expect("invalid file path");
+    let is_elf = elf::is_elf(&mut file).expect("failed to read input file");
+    if !is_elf && !image && !compress {
+        let len = file.metadata().expect("failed to read file metadata").len();
+        return (Box::new(BufReader::new(file)), len as usize);
+    }
+
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).expect("failed to read input file");
+    let (data, entry) = if is_elf {
+        let image = elf::extract(&contents).unwrap_or_else(|e| {
+            eprintln!("error: {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        if image.entry != BINARY_START_ADDR as u64 {
+            eprintln!("warning: {}: entry point {:#x} isn't the load address {:#x}",
+                      path.display(), image.entry, BINARY_START_ADDR as u64);
+        }
+        println!("Extracted a {}-byte binary from ELF file {}", image.data.len(), path.display());
+        (image.data, image.entry)
+    } else {
+        (contents, BINARY_START_ADDR as u64)
+    };
+
+    let unpacked = data.len();
+    let (data, flags) = if compress {
+        let mut compressed = vec![0; xmodem::max_compressed_len(data.len())];
+        let len = xmodem::compress(&data, &mut compressed).expect("buffer holds the compressed image");
+        compressed.truncate(len);
+        println!("Compressed {} bytes to {} with LZ4", unpacked, len);
+        (compressed, IMAGE_FLAG_LZ4)
+    } else {
+        (data, 0)
+    };
+
+    if IMAGE_HEADER_LEN + std::cmp::max(unpacked, data.len()) > MAX_BINARY_SIZE {
+        eprintln!("error: {}: image larger than the bootloader's maximum of {} bytes",
+                  path.display(), MAX_BINARY_SIZE - IMAGE_HEADER_LEN);
+        std::process::exit(1);
+    }
+
+    let header = ImageHeader::new(BINARY_START_ADDR as u64, entry, &data, flags);
+    println!("Framed {} as a boot image: {} bytes, CRC-32 {:#010x}", path.display(), header.len, header.crc);
+    let mut framed = header.encode().to_vec();
+    framed.extend_from_slice(&data);
+    let len = framed.len();
+    (Box::new(std::io::Cursor::new(framed)), len)
+}
+
+/// Returns the writer for a received file described by `header`, if the
+/// protocol sent one: a file named after it in `dir` if set, otherwise `file`
+/// if set, otherwise stdout.
+fn output_for<'a>(file: &'a mut Option<File>, dir: Option<&Path>, header: Option<&Header>) -> Box<dyn Write + 'a> {
+    if let Some(dir) = dir {
+        // Never let the sender pick a path outside of `dir`.
+        let name = header.and_then(|h| Path::new(h.name()).file_name()).unwrap_or(OsStr::new("received"));
+        let path = dir.join(name);
+        return Box::new(File::create(&path).expect("failed to create output file"));
+    }
+
+    match file {
+        Some(file) => Box::new(file),
+        None => Box::new(std::io::stdout()),
+    }
+}
+
+/// Receives files from `port` into the output set in `opt`. YMODEM and ZMODEM
+/// files are truncated to the size in their headers; XMODEM data keeps its
+/// padding to a multiple of 128 bytes.
+fn receive<T: std::io::Read + Write>(opt: &Opt, port: T, config: XmodemConfig) {
+    // Keep stdout clean for the data when it receives it.
+    let quiet = opt.output.is_none();
+    let progress: ProgressFn = if quiet { quiet_fn } else { progress_fn };
+    let dir = opt.output.as_deref().filter(|p| p.is_dir());
+    let mut file = match (&opt.output, dir) {
+        (Some(path), None) => Some(File::create(path).expect("failed to create output file")),
+        _ => None,
+    };
 
+    let mut num_bytes = 0;
+    if opt.ymodem {
+        let mut ymodem = Ymodem::new_with_progress(port, progress);
+        ymodem.set_clock(now);
+        ymodem.set_config(config);
+        while let Some(header) = ymodem.next_file().expect("ymodem reception failed") {
+            let into = output_for(&mut file, dir, Some(&header));
+            num_bytes += ymodem.receive_file(&header, into).expect("ymodem reception failed");
+            if !quiet {
+                println!();
+                println!("Received {:?}", header);
+            }
+        }
+    } else if opt.zmodem {
+        let mut zmodem = Zmodem::new_with_progress(port, progress);
+        zmodem.set_clock(now);
+        while let Some(header) = zmodem.next_file().expect("zmodem reception failed") {
+            let into = output_for(&mut file, dir, Some(&header));
+            num_bytes += zmodem.receive_file(&header, into, 0).expect("zmodem reception failed");
+            if !quiet {
+                println!();
+                println!("Received {:?}", header);
+            }
+        }
+    } else {
+        let mut receiver = Xmodem::new_with_progress(port, progress);
+        receiver.set_mode(Mode::Crc);
+        receiver.set_clock(now);
+        receiver.set_config(config);
+        let mut into = output_for(&mut file, dir, None);
+        let mut resumes = 0;
+        loop {
+            match receiver.receive_data(&mut into, None) {
+                Ok(_) => break,
+                Err(e) if resumes < opt.resume => {
+                    resumes += 1;
+                    let at = receiver.position();
+                    if !quiet {
+                        println!();
+                        println!("Transfer interrupted ({}); resuming at block {}", e, at.block());
+                    }
+                    receiver.resume(at);
+                    receiver.purge().expect("xmodem reception failed");
+                }
+                Err(e) => panic!("xmodem reception failed: {:?}", e),
+            }
+        }
+        num_bytes = receiver.position().offset();
+        if !quiet {
+            println!();
+        }
+    }
+
+    if !quiet {
+        println!("Done: {} bytes read in total", num_bytes);
+    }
+}
+
+fn main() {
     let opt = Opt::from_args();
+    if opt.input.len() > 1 && !opt.ymodem && !opt.zmodem {
+        eprintln!("error: multiple input files require YMODEM (-y) or ZMODEM (-z)");
+        std::process::exit(1);
+    }
+
+    let receiving = opt.receive || opt.output.is_some();
+    if receiving && (opt.raw || !opt.input.is_empty()) {
+        eprintln!("error: receiving doesn't support raw mode (-r) or input files (-i)");
+        std::process::exit(1);
+    }
+    if (opt.image || opt.compress) && opt.input.is_empty() {
+        eprintln!("error: boot images (-I, -C) require an input file (-i)");
+        std::process::exit(1);
+    }
+    if opt.console && receiving {
+        eprintln!("error: the console (-c) can't be combined with receiving");
+        std::process::exit(1);
+    }
+
     let mut port = serial::open(&opt.tty_path).expect("path points to invalid TTY");
     (&mut port).set_timeout(Duration::new(opt.timeout, 0)).expect("failed to set timeout");
     let mut settings = (&port).read_settings().expect("failed to read settings");
@@ -68,29 +329,150 @@ fn main() {
 
 
     let mut to = port;
-    let mut buf_reader = None;
-    if let Some(input_path_buf) = opt.input {
-        let file = File::open(input_path_buf).expect("invalid file path");
-        buf_reader = Some(BufReader::new(file));
+    let config = XmodemConfig { max_retries: opt.retries, double_can: true, ..XmodemConfig::default() };
+    // The board may still be resetting or running its old kernel, so with a
+    // console the first transfer waits for it indefinitely. Transfers started
+    // from the console can't be interrupted, so they keep the bounded wait.
+    let first = if opt.console {
+        XmodemConfig { handshake_retries: usize::MAX, ..config }
+    } else {
+        config
+    };
+    if receiving {
+        receive(&opt, to, first);
+        return;
     }
 
+    let size = if opt.one_k { PacketSize::OneK } else { PacketSize::Standard };
+    if !opt.console || !opt.input.is_empty() {
+        send(&opt, &mut to, first, size);
+    }
+
+    if opt.console {
+        let settings = console::Settings {
+            log: opt.log.clone(),
+            timeout: Duration::new(opt.timeout, 0),
+            config,
+            size,
+        };
+        console::run(&mut to, &settings).expect("console failed");
+    }
+}
+
+/// Sends the input files set in `opt`, or stdin if there are none, to `to`.
+fn send(opt: &Opt, to: &mut serial::SystemPort, config: XmodemConfig, size: PacketSize) {
+    use std::io::{self, SeekFrom};
+
+    if opt.ymodem {
+        let mut ymodem = Ymodem::new_with_progress(&mut *to, progress_fn);
+        ymodem.set_clock(now);
+        ymodem.set_config(config);
+        let mut num_bytes = 0;
+        if opt.input.is_empty() {
+            let header = Header::new("stdin", None, None).expect("valid file name");
+            num_bytes += ymodem.send_file(&header, io::stdin(), size)
+                .expect("ymodem transmission failed");
+        }
+        for path in &opt.input {
+            let (data, len) = open_input(path, opt.image, opt.compress);
+            let header = file_header(path, len);
+            num_bytes += ymodem.send_file(&header, data, size)
+                .expect("ymodem transmission failed");
+            println!();
+            println!("Sent {:?}", header);
+        }
+        ymodem.finish().expect("ymodem transmission failed");
+        println!("Done: {} bytes written in total", num_bytes);
+        return;
+    }
+
+    if opt.zmodem {
+        let mut zmodem = Zmodem::new_with_progress(&mut *to, progress_fn);
+        zmodem.set_clock(now);
+        let mut num_bytes = 0;
+        if opt.input.is_empty() {
+            // Recovering from errors requires seeking, so buffer stdin.
+            let mut data = vec![];
+            io::Read::read_to_end(&mut io::stdin(), &mut data).expect("failed to read stdin");
+            let header = Header::new("stdin", Some(data.len()), None).expect("valid file name");
+            num_bytes += zmodem.send_file(&header, io::Cursor::new(data))
+                .expect("zmodem transmission failed");
+        }
+        for path in &opt.input {
+            let (data, len) = open_input(path, opt.image, opt.compress);
+            let header = file_header(path, len);
+            num_bytes += zmodem.send_file(&header, data)
+                .expect("zmodem transmission failed");
+            println!();
+            println!("Sent {:?}", header);
+        }
+        zmodem.finish().expect("zmodem transmission failed");
+        println!("Done: {} bytes written in total", num_bytes);
+        return;
+    }
+
+    let input = opt.input.first().map(|path| open_input(path, opt.image, opt.compress));
+
     if opt.raw {
         let num_bytes;
-        if let Some(mut reader) = buf_reader {
-            num_bytes = io::copy(&mut reader, &mut to).expect("raw transmission failed");
+        if let Some((mut reader, _)) = input {
+            num_bytes = io::copy(&mut reader, to).expect("raw transmission failed");
         } else {
-            num_bytes = io::copy(&mut io::stdin(), &mut to).expect("raw transmission failed");
+            num_bytes = io::copy(&mut io::stdin(), to).expect("raw transmission failed");
         }
         println!("Done: {} bytes written in total", num_bytes);
     } else {
-        let num_bytes;
-        if let Some(reader) = buf_reader {
-            num_bytes = Xmodem::transmit_with_progress(reader, to, progress_fn)
-                .expect("xmodem transmission failed");
+        let mut transmitter = Xmodem::new_with_progress(&mut *to, progress_fn);
+        transmitter.set_mode(Mode::Crc);
+        transmitter.set_clock(now);
+        transmitter.set_config(config);
+        let mut num_bytes = 0;
+        if let Some((mut reader, len)) = input {
+            transmitter.set_total(Some(len));
+            // Only wait out a board that isn't ready when we expect one to be.
+            let wait = opt.console || opt.restart > 0;
+            let (mut resumes, mut restarts) = (0, 0);
+            loop {
+                let at = match transmitter.send_data(&mut reader, size) {
+                    Ok(n) => {
+                        num_bytes += n;
+                        break;
+                    }
+                    Err(ref e) if wait && e.kind() == io::ErrorKind::InvalidData
+                        && transmitter.position() == Resume::default() => {
+                        // Whatever the board printed before its bootloader
+                        // asked for the first packet; keep waiting.
+                        Resume::default()
+                    }
+                    Err(e) if resumes < opt.resume => {
+                        resumes += 1;
+                        let at = transmitter.position();
+                        println!();
+                        println!("Transfer interrupted ({}); resuming at block {}", e, at.block());
+                        at
+                    }
+                    Err(e) if restarts < opt.restart => {
+                        restarts += 1;
+                        println!();
+                        println!("Transfer failed ({}); waiting for the receiver to restart", e);
+                        transmitter.reset_stats();
+                        transmitter.set_total(Some(len));
+                        Resume::default()
+                    }
+                    Err(e) => panic!("xmodem transmission failed: {:?}", e),
+                };
+                num_bytes = at.offset();
+                reader.seek(SeekFrom::Start(at.offset() as u64)).expect("failed to seek input");
+                transmitter.resume(at);
+                if at != Resume::default() {
+                    transmitter.purge().expect("xmodem transmission failed");
+                }
+            }
         } else {
-            num_bytes = Xmodem::transmit_with_progress(io::stdin(), to, progress_fn)
-            .expect("xmodem transmission failed");
+            num_bytes = transmitter.send_data(io::stdin(), size)
+                .expect("xmodem transmission failed");
         }
+        println!();
         println!("Done: {} bytes written in total", num_bytes);
     }
 }
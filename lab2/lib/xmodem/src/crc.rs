@@ -0,0 +1,31 @@
+/// Computes the CRC-16/XMODEM (polynomial `0x1021`, initial value `0`) of `buf`.
+pub(crate) fn get_crc(buf: &[u8]) -> u16 {
+    update_crc(0, buf)
+}
+
+/// Updates the CRC-16/XMODEM `crc` with the bytes of `buf`.
+pub(crate) fn update_crc(crc: u16, buf: &[u8]) -> u16 {
+    buf.iter().fold(crc, |crc, b| {
+        let mut crc = crc ^ ((*b as u16) << 8);
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Computes the CRC-32 (IEEE 802.3) of `buf`.
+pub fn get_crc32(buf: &[u8]) -> u32 {
+    !update_crc32(!0, buf)
+}
+
+/// Updates the CRC-32 `crc`, kept inverted, with the bytes of `buf`.
+pub(crate) fn update_crc32(crc: u32, buf: &[u8]) -> u32 {
+    buf.iter().fold(crc, |crc, b| {
+        let mut crc = crc ^ (*b as u32);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+        crc
+    })
+}
@@ -0,0 +1,211 @@
+use core::fmt;
+
+use crate::lz4;
+use crate::crc::get_crc32;
+
+/// Magic number starting every boot image header.
+pub const IMAGE_MAGIC: [u8; 4] = *b"BIMG";
+
+/// Version of the boot image header format.
+pub const IMAGE_VERSION: u16 = 1;
+
+/// Length in bytes of an encoded boot image header.
+pub const IMAGE_HEADER_LEN: usize = 32;
+
+/// Flag of images compressed with `compress()`.
+pub const IMAGE_FLAG_LZ4: u16 = 1 << 0;
+
+/// Flags understood by this version of the format.
+const KNOWN_FLAGS: u16 = IMAGE_FLAG_LZ4;
+
+/// Address the bootloader loads images at.
+pub const BINARY_START_ADDR: usize = 0x80000;
+
+/// Address the bootloader itself is linked at.
+pub const BOOTLOADER_START_ADDR: usize = 0x4000000;
+
+/// Space reserved for the bootloader's stack, which grows down from
+/// `BOOTLOADER_START_ADDR`.
+pub const BOOTLOADER_STACK_SIZE: usize = 0x10000;
+
+/// Largest image the bootloader accepts, header included: the free space
+/// between `BINARY_START_ADDR` and the bootloader's stack.
+pub const MAX_BINARY_SIZE: usize = BOOTLOADER_START_ADDR - BOOTLOADER_STACK_SIZE - BINARY_START_ADDR;
+
+/// Header framing a kernel image sent to the bootloader.
+///
+/// On the wire, the header precedes the image and is encoded in little-endian
+/// order: the magic `BIMG`, the format version and flags as `u16`s, the load
+/// address and entry point as `u64`s, and the image's length and CRC-32 as
+/// `u32`s.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Flags describing the image, like `IMAGE_FLAG_LZ4`.
+    pub flags: u16,
+    /// Address the image must be loaded at.
+    pub load_addr: u64,
+    /// Address to jump to once the image is loaded.
+    pub entry: u64,
+    /// Length of the image as sent, compressed or not, in bytes.
+    pub len: usize,
+    /// CRC-32 (IEEE 802.3) of the image as sent.
+    pub crc: u32,
+}
+
+/// Reasons a boot image is rejected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// Fewer bytes than the header or the image's length were received.
+    Truncated { expected: usize, received: usize },
+    /// The header doesn't start with `IMAGE_MAGIC`.
+    BadMagic([u8; 4]),
+    /// The header's format version isn't `IMAGE_VERSION`.
+    UnsupportedVersion(u16),
+    /// The header sets flags this version doesn't understand.
+    UnsupportedFlags(u16),
+    /// The image's CRC-32 doesn't match the header's.
+    BadCrc { expected: u32, actual: u32 },
+    /// The image is larger than the space it's unpacked in.
+    TooLarge { len: usize, max: usize },
+    /// The compressed image is malformed.
+    BadCompression,
+    /// The image would overwrite its compressed data while decompressing.
+    NoRoom,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Truncated { expected, received } => {
+                write!(f, "truncated image: expected {} bytes, received {}", expected, received)
+            }
+            ImageError::BadMagic(magic) => write!(f, "bad image magic {:02x?}", magic),
+            ImageError::UnsupportedVersion(version) => write!(f, "unsupported image version {}", version),
+            ImageError::UnsupportedFlags(flags) => write!(f, "unsupported image flags {:#06x}", flags),
+            ImageError::BadCrc { expected, actual } => {
+                write!(f, "image CRC-32 {:#010x} doesn't match header's {:#010x}", actual, expected)
+            }
+            ImageError::TooLarge { len, max } => {
+                write!(f, "image of {} bytes larger than the {} bytes available", len, max)
+            }
+            ImageError::BadCompression => write!(f, "malformed compressed image"),
+            ImageError::NoRoom => write!(f, "not enough room to decompress the image in place"),
+        }
+    }
+}
+
+impl ImageHeader {
+    /// Returns the header for the image `data` that is loaded at `load_addr`
+    /// and entered at `entry`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is 4GiB or longer.
+    pub fn new(load_addr: u64, entry: u64, data: &[u8], flags: u16) -> ImageHeader {
+        assert!(data.len() <= u32::MAX as usize, "image too large");
+        ImageHeader { flags, load_addr, entry, len: data.len(), crc: get_crc32(data) }
+    }
+
+    /// Encodes `self` for the wire.
+    pub fn encode(&self) -> [u8; IMAGE_HEADER_LEN] {
+        let mut buf = [0u8; IMAGE_HEADER_LEN];
+        buf[0..4].copy_from_slice(&IMAGE_MAGIC);
+        buf[4..6].copy_from_slice(&IMAGE_VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.load_addr.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.entry.to_le_bytes());
+        buf[24..28].copy_from_slice(&(self.len as u32).to_le_bytes());
+        buf[28..32].copy_from_slice(&self.crc.to_le_bytes());
+        buf
+    }
+
+    /// Decodes the header at the start of `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `buf` is shorter than a header, or if the header's
+    /// magic, version or flags aren't understood.
+    pub fn decode(buf: &[u8]) -> Result<ImageHeader, ImageError> {
+        if buf.len() < IMAGE_HEADER_LEN {
+            return Err(ImageError::Truncated { expected: IMAGE_HEADER_LEN, received: buf.len() });
+        }
+
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&buf[0..4]);
+        if magic != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic(magic));
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes([buf[at], buf[at + 1]]);
+        let u32_at = |at: usize| u32::from(u16_at(at)) | u32::from(u16_at(at + 2)) << 16;
+        let u64_at = |at: usize| u64::from(u32_at(at)) | u64::from(u32_at(at + 4)) << 32;
+
+        let version = u16_at(4);
+        if version != IMAGE_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let flags = u16_at(6);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(ImageError::UnsupportedFlags(flags));
+        }
+
+        Ok(ImageHeader {
+            flags,
+            load_addr: u64_at(8),
+            entry: u64_at(16),
+            len: u32_at(24) as usize,
+            crc: u32_at(28),
+        })
+    }
+
+    /// Checks that `data`, as received after the header, holds the complete
+    /// image. Bytes past the image's length, like XMODEM padding, are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `data` is shorter than the image or the image's
+    /// CRC-32 doesn't match.
+    pub fn verify(&self, data: &[u8]) -> Result<(), ImageError> {
+        if data.len() < self.len {
+            return Err(ImageError::Truncated { expected: self.len, received: data.len() });
+        }
+
+        let actual = get_crc32(&data[..self.len]);
+        if actual != self.crc {
+            return Err(ImageError::BadCrc { expected: self.crc, actual });
+        }
+
+        Ok(())
+    }
+
+    /// Moves the image, received after the header at the start of `buf`, to
+    /// the start of `buf`, decompressing it if it's compressed. The rest of
+    /// `buf` is scratch space. Returns the length of the unpacked image.
+    ///
+    /// The image should have been checked with [`ImageHeader::verify()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `buf` doesn't hold the whole image after the
+    /// header or is too short for the decompressed image, or if the
+    /// compressed image is malformed.
+    pub fn unpack(&self, buf: &mut [u8]) -> Result<usize, ImageError> {
+        let end = IMAGE_HEADER_LEN + self.len;
+        if buf.len() < end {
+            let received = buf.len().saturating_sub(IMAGE_HEADER_LEN);
+            return Err(ImageError::Truncated { expected: self.len, received });
+        }
+
+        if self.flags & IMAGE_FLAG_LZ4 == 0 {
+            buf.copy_within(IMAGE_HEADER_LEN..end, 0);
+            return Ok(self.len);
+        }
+
+        // Decompressing from the end of `buf` leaves the most room between
+        // the image and the compressed data that's still to be read.
+        let start = buf.len() - self.len;
+        buf.copy_within(IMAGE_HEADER_LEN..end, start);
+        lz4::decompress_in_place(buf, start, self.len)
+    }
+}
@@ -2,29 +2,172 @@
 
 #![feature(decl_macro)]
 
+use core::time::Duration;
+
 use shim::io;
 use shim::ioerr;
 
 #[cfg(test)] mod tests;
+#[cfg(test)] mod lossy;
 mod read_ext;
+mod crc;
+mod image;
+mod lz4;
+mod machine;
 mod progress;
-
-pub use progress::{Progress, ProgressFn};
-
+mod ymodem;
+mod zmodem;
+
+pub use crc::get_crc32;
+pub use image::{ImageError, ImageHeader, IMAGE_FLAG_LZ4, IMAGE_HEADER_LEN, IMAGE_MAGIC, IMAGE_VERSION};
+pub use image::{BINARY_START_ADDR, BOOTLOADER_STACK_SIZE, BOOTLOADER_START_ADDR, MAX_BINARY_SIZE};
+pub use lz4::{compress, max_compressed_len};
+pub use machine::{Event, Receiver, Transmitter};
+pub use progress::{Progress, ProgressFn, TransferStats};
+pub use ymodem::{Header, Ymodem, MAX_NAME_LEN};
+pub use zmodem::{Zmodem, SUBPACKET_LEN};
+
+use crc::get_crc;
 use read_ext::ReadExt;
 
 const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
 const EOT: u8 = 0x04;
 const ACK: u8 = 0x06;
 const NAK: u8 = 0x15;
 const CAN: u8 = 0x18;
+const CRC: u8 = b'C';
+
+/// Number of unanswered `'C'` bytes a receiver sends before falling back to
+/// checksum mode.
+const CRC_ATTEMPTS: usize = 3;
+
+/// The error detection scheme used for the packets of a transfer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// The original XMODEM scheme: a single byte sum of the packet's data.
+    Checksum,
+    /// XMODEM-CRC: a big-endian CRC-16/XMODEM of the packet's data.
+    Crc,
+}
+
+/// The size of the data blocks sent by a transmitter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PacketSize {
+    /// 128-byte `SOH` packets, understood by every XMODEM receiver.
+    Standard,
+    /// XMODEM-1K: 1024-byte `STX` packets.
+    OneK,
+}
+
+impl PacketSize {
+    /// Returns the number of data bytes in a packet of this size.
+    pub fn data_len(self) -> usize {
+        match self {
+            PacketSize::Standard => 128,
+            PacketSize::OneK => 1024,
+        }
+    }
+}
+
+/// The position of a transfer: the absolute number of the next block and the
+/// byte offset of its data. Used as a token to resume an interrupted transfer.
+///
+/// Unlike the packet number sent on the wire, the block number doesn't wrap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Resume {
+    block: usize,
+    offset: usize,
+}
+
+impl Resume {
+    /// Returns a position with the next block numbered `block` starting at
+    /// byte `offset` of the transferred data.
+    pub fn new(block: usize, offset: usize) -> Resume {
+        Resume { block, offset }
+    }
+
+    /// Returns the absolute number of the next block, starting at 1.
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    /// Returns the byte offset of the next block's data.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Advances past a block of `len` bytes.
+    fn advance(&mut self, len: usize) {
+        self.block += 1;
+        self.offset += len;
+    }
+}
+
+impl Default for Resume {
+    /// Returns the position at the start of a transfer.
+    fn default() -> Resume {
+        Resume::new(1, 0)
+    }
+}
+
+/// Retry, timeout and cancellation policy of an [`Xmodem`] transfer.
+///
+/// The defaults suit a line whose reads time out after several seconds, such
+/// as a PL011 UART or a pty. On a line with a short read timeout, like the
+/// Pi's mini UART, set `byte_timeout` and a clock so that a single timed out
+/// read doesn't fail the transfer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct XmodemConfig {
+    /// Number of times a packet is retried after it was rejected before the
+    /// transfer fails.
+    pub max_retries: usize,
+    /// Number of times the handshake for the first packet is retried after
+    /// waiting for the other side timed out. A CRC receiver falls back to
+    /// checksum mode after at most 3 of these retries.
+    pub handshake_retries: usize,
+    /// Time to wait for each byte. Reads failing with `TimedOut` or
+    /// `WouldBlock` are retried until it has passed. Requires a clock to be
+    /// set with [`Xmodem::set_clock()`]; otherwise the first such failure is
+    /// returned.
+    pub byte_timeout: Option<Duration>,
+    /// Whether to cancel a transfer with two `CAN` bytes instead of one.
+    pub double_can: bool,
+    /// Whether to discard pending input until the line is quiet before
+    /// rejecting a packet with `NAK`, so that the sender's retry starts on a
+    /// clean line. Requires reads of the inner stream to time out.
+    pub purge_before_nak: bool,
+}
+
+impl Default for XmodemConfig {
+    /// Returns a policy of 9 retries per packet, for 10 attempts, and 3 for
+    /// the handshake, without a byte timeout, purging, or double `CAN`.
+    fn default() -> XmodemConfig {
+        XmodemConfig {
+            max_retries: 9,
+            handshake_retries: CRC_ATTEMPTS,
+            byte_timeout: None,
+            double_can: false,
+            purge_before_nak: false,
+        }
+    }
+}
 
 /// Implementation of the XMODEM protocol.
-pub struct Xmodem<R> {
-    packet: u8,
-    started: bool,
+///
+/// The progress callback `P` may be any `FnMut(Progress)`, including closures
+/// that keep state between calls.
+pub struct Xmodem<R, P = ProgressFn> {
+    rx: Receiver,
+    tx: Transmitter,
+    position: Resume,
+    mode: Mode,
+    config: XmodemConfig,
+    stats: TransferStats,
+    clock: Option<fn() -> Duration>,
+    start: Option<Duration>,
     inner: R,
-    progress: ProgressFn
+    progress: P
 }
 
 impl Xmodem<()> {
@@ -32,90 +175,248 @@ impl Xmodem<()> {
     /// length of the total data yielded by `data` is not a multiple of 128
     /// bytes, the data is padded with zeroes and sent to the receiver.
     ///
+    /// The transmitter accepts both checksum and CRC receivers.
+    ///
     /// Returns the number of bytes written to `to`, excluding padding zeroes.
     #[inline]
     pub fn transmit<R, W>(data: R, to: W) -> io::Result<usize>
         where W: io::Read + io::Write, R: io::Read
     {
-        Xmodem::transmit_with_progress(data, to, progress::noop)
+        Xmodem::transmit_with_progress(data, to, Mode::Crc, PacketSize::Standard, progress::noop)
+            .map(|stats| stats.bytes)
     }
 
     /// Transmits `data` to the receiver `to` using the XMODEM protocol. If the
     /// length of the total data yielded by `data` is not a multiple of 128
     /// bytes, the data is padded with zeroes and sent to the receiver.
     ///
+    /// If `mode` is `Mode::Crc`, the transmitter uses whichever mode the
+    /// receiver asks for. If `mode` is `Mode::Checksum`, requests for CRC mode
+    /// are ignored until the receiver falls back to checksum mode.
+    ///
+    /// Data is sent in packets of `size`. With `PacketSize::OneK`, a final
+    /// chunk shorter than 1024 bytes is sent as 128-byte packets instead to
+    /// keep padding small.
+    ///
     /// The function `f` is used as a callback to indicate progress throughout
     /// the transmission. See the [`Progress`] enum for more information.
     ///
-    /// Returns the number of bytes written to `to`, excluding padding zeroes.
-    pub fn transmit_with_progress<R, W>(mut data: R, to: W, f: ProgressFn) -> io::Result<usize>
-        where W: io::Read + io::Write, R: io::Read
+    /// Returns a summary of the transmission. Its `bytes` are the number of
+    /// bytes written to `to`, excluding padding zeroes.
+    pub fn transmit_with_progress<R, W, P>(
+        data: R,
+        to: W,
+        mode: Mode,
+        size: PacketSize,
+        f: P
+    ) -> io::Result<TransferStats>
+        where W: io::Read + io::Write, R: io::Read, P: FnMut(Progress)
     {
-        let mut transmitter = Xmodem::new_with_progress(to, f);
-        let mut packet = [0u8; 128];
-        let mut written = 0;
-        'next_packet: loop {
-            let n = data.read_max(&mut packet)?;
-            packet[n..].iter_mut().for_each(|b| *b = 0);
+        Xmodem::transmit_with_config(data, to, mode, size, XmodemConfig::default(), f)
+    }
 
-            if n == 0 {
-                transmitter.write_packet(&[])?;
-                return Ok(written);
-            }
+    /// Transmits `data` to the receiver `to` using the XMODEM protocol like
+    /// [`Xmodem::transmit_with_progress()`], retrying, timing out and
+    /// cancelling according to `config`.
+    pub fn transmit_with_config<R, W, P>(
+        data: R,
+        to: W,
+        mode: Mode,
+        size: PacketSize,
+        config: XmodemConfig,
+        f: P
+    ) -> io::Result<TransferStats>
+        where W: io::Read + io::Write, R: io::Read, P: FnMut(Progress)
+    {
+        Xmodem::transmit_from(data, to, mode, size, config, f, &mut Resume::default())
+    }
 
-            for _ in 0..10 {
-                match transmitter.write_packet(&packet) {
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e),
-                    Ok(_) => {
-                        written += n;
-                        continue 'next_packet;
-                    }
-                }
-            }
+    /// Transmits `data` to the receiver `to` using the XMODEM protocol like
+    /// [`Xmodem::transmit_with_progress()`], starting at position `at`.
+    ///
+    /// `data` must yield the transferred data from byte `at.offset()` onwards.
+    /// Whether the transfer succeeds or fails, `at` is updated to the position
+    /// after the last packet acknowledged by the receiver. After a failure,
+    /// calling this function again with the updated `at`, `data` seeked to
+    /// `at.offset()` and a receiver resumed at the same position continues the
+    /// transfer instead of starting over. Input left over from the interrupted
+    /// transfer should be discarded first, see [`Xmodem::purge()`].
+    ///
+    /// Returns a summary of this call's part of the transmission. Its `bytes`
+    /// are the number of bytes written to `to`, excluding padding zeroes.
+    pub fn transmit_resumable<R, W, P>(
+        data: R,
+        to: W,
+        mode: Mode,
+        size: PacketSize,
+        f: P,
+        at: &mut Resume
+    ) -> io::Result<TransferStats>
+        where W: io::Read + io::Write, R: io::Read, P: FnMut(Progress)
+    {
+        Xmodem::transmit_from(data, to, mode, size, XmodemConfig::default(), f, at)
+    }
 
-            return ioerr!(BrokenPipe, "bad transmit");
-        }
+    /// Transmits `data` to `to` from position `at` with the policy `config`.
+    /// See [`Xmodem::transmit_resumable()`].
+    fn transmit_from<R, W, P>(
+        data: R,
+        to: W,
+        mode: Mode,
+        size: PacketSize,
+        config: XmodemConfig,
+        f: P,
+        at: &mut Resume
+    ) -> io::Result<TransferStats>
+        where W: io::Read + io::Write, R: io::Read, P: FnMut(Progress)
+    {
+        let mut transmitter = Xmodem::new_with_progress(to, f);
+        transmitter.set_mode(mode);
+        transmitter.set_config(config);
+        transmitter.resume(*at);
+        let result = transmitter.send_data(data, size);
+        *at = transmitter.position();
+        result.map(|bytes| TransferStats { bytes, ..transmitter.stats() })
     }
 
     /// Receives `data` from `from` using the XMODEM protocol and writes it into
     /// `into`. Returns the number of bytes read from `from`, a multiple of 128.
+    ///
+    /// The receiver requests checksum mode.
     #[inline]
     pub fn receive<R, W>(from: R, into: W) -> io::Result<usize>
        where R: io::Read + io::Write, W: io::Write
     {
-        Xmodem::receive_with_progress(from, into, progress::noop)
+        Xmodem::receive_with_progress(from, into, Mode::Checksum, progress::noop)
+            .map(|stats| stats.bytes)
     }
 
     /// Receives `data` from `from` using the XMODEM protocol and writes it into
     /// `into`. Returns the number of bytes read from `from`, a multiple of 128.
+    /// Both 128-byte and XMODEM-1K packets are accepted, in any order.
+    ///
+    /// The receiver requests the error detection scheme `mode`. If `mode` is
+    /// `Mode::Crc` and the sender doesn't answer the first few `'C'` requests
+    /// before `from` times out, the receiver falls back to checksum mode.
     ///
     /// The function `f` is used as a callback to indicate progress throughout
     /// the reception. See the [`Progress`] enum for more information.
-    pub fn receive_with_progress<R, W>(from: R, mut into: W, f: ProgressFn) -> io::Result<usize>
-       where R: io::Read + io::Write, W: io::Write
+    ///
+    /// Returns a summary of the reception. Its `bytes` are the number of bytes
+    /// read from `from`, a multiple of 128.
+    pub fn receive_with_progress<R, W, P>(from: R, into: W, mode: Mode, f: P) -> io::Result<TransferStats>
+       where R: io::Read + io::Write, W: io::Write, P: FnMut(Progress)
+    {
+        Xmodem::receive_with_config(from, into, mode, XmodemConfig::default(), f)
+    }
+
+    /// Receives `data` from `from` using the XMODEM protocol like
+    /// [`Xmodem::receive_with_progress()`], retrying, timing out and
+    /// cancelling according to `config`.
+    pub fn receive_with_config<R, W, P>(
+        from: R,
+        into: W,
+        mode: Mode,
+        config: XmodemConfig,
+        f: P
+    ) -> io::Result<TransferStats>
+       where R: io::Read + io::Write, W: io::Write, P: FnMut(Progress)
+    {
+        Xmodem::receive_from(from, into, mode, config, f, &mut Resume::default())
+    }
+
+    /// Receives `data` from `from` using the XMODEM protocol like
+    /// [`Xmodem::receive_with_progress()`], starting at position `at`.
+    ///
+    /// `into` receives the transferred data from byte `at.offset()` onwards.
+    /// Whether the transfer succeeds or fails, `at` is updated to the position
+    /// after the last packet acknowledged to the sender. After a failure,
+    /// calling this function again with the updated `at` and a sender resumed
+    /// at the same position continues the transfer instead of starting over.
+    /// Input left over from the interrupted transfer should be discarded
+    /// first, see [`Xmodem::purge()`]. A transfer that failed because writing
+    /// to `into` failed can't be resumed.
+    ///
+    /// Returns a summary of this call's part of the reception. Its `bytes` are
+    /// the number of bytes read from `from`, a multiple of 128.
+    pub fn receive_resumable<R, W, P>(
+        from: R,
+        into: W,
+        mode: Mode,
+        f: P,
+        at: &mut Resume
+    ) -> io::Result<TransferStats>
+       where R: io::Read + io::Write, W: io::Write, P: FnMut(Progress)
+    {
+        Xmodem::receive_from(from, into, mode, XmodemConfig::default(), f, at)
+    }
+
+    /// Receives from `from` into `into` from position `at` with the policy
+    /// `config`. See [`Xmodem::receive_resumable()`].
+    fn receive_from<R, W, P>(
+        from: R,
+        into: W,
+        mode: Mode,
+        config: XmodemConfig,
+        f: P,
+        at: &mut Resume
+    ) -> io::Result<TransferStats>
+       where R: io::Read + io::Write, W: io::Write, P: FnMut(Progress)
     {
         let mut receiver = Xmodem::new_with_progress(from, f);
-        let mut packet = [0u8; 128];
-        let mut received = 0;
-        'next_packet: loop {
-            for _ in 0..10 {
-                match receiver.read_packet(&mut packet) {
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e),
-                    Ok(0) => break 'next_packet,
-                    Ok(n) => {
-                        received += n;
-                        into.write_all(&packet)?;
-                        continue 'next_packet;
-                    }
-                }
-            }
+        receiver.set_mode(mode);
+        receiver.set_config(config);
+        receiver.resume(*at);
+        let result = receiver.receive_data(into, None);
+        *at = receiver.position();
+        result.map(|bytes| TransferStats { bytes, ..receiver.stats() })
+    }
+}
 
-            return ioerr!(BrokenPipe, "bad receive");
+/// Returns `true` if `e` indicates that a read timed out.
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Writes the bytes yielded by `next` to `inner`, flushing it if there were
+/// any.
+fn write_output<W, F>(inner: &mut W, mut next: F) -> io::Result<()>
+    where W: io::Write, F: FnMut() -> Option<u8>
+{
+    let mut buf = [0u8; 64];
+    let mut n = 0;
+    let mut written = false;
+    while let Some(byte) = next() {
+        buf[n] = byte;
+        n += 1;
+        if n == buf.len() {
+            inner.write_all(&buf)?;
+            n = 0;
+            written = true;
         }
+    }
 
-        Ok(received)
+    if n > 0 {
+        inner.write_all(&buf[..n])?;
+        written = true;
+    }
+    if written {
+        inner.flush()?;
+    }
+    Ok(())
+}
+
+/// Discards input from `inner` until a read times out or the stream ends.
+fn drain<R: io::Read>(inner: &mut R) -> io::Result<()> {
+    let mut buf = [0u8; 64];
+    loop {
+        match inner.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => continue,
+            Err(ref e) if is_timeout(e) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
     }
 }
 
@@ -127,104 +428,337 @@ impl<T: io::Read + io::Write> Xmodem<T> {
     /// Returns a new `Xmodem` instance with the internal reader/writer set to
     /// `inner`. The returned instance can be used for both receiving
     /// (downloading) and sending (uploading).
+    ///
+    /// The instance starts out in checksum mode. Use [`Xmodem::set_mode()`] to
+    /// change it.
     pub fn new(inner: T) -> Self {
-        Xmodem { packet: 1, started: false, inner, progress: progress::noop}
+        Xmodem::new_with_progress(inner, progress::noop)
     }
 
+    /// Returns a new `Xmodem` instance with the internal reader/writer set to
+    /// `inner` that retries, times out and cancels according to `config`.
+    pub fn new_with_config(inner: T, config: XmodemConfig) -> Self {
+        let mut xmodem = Xmodem::new(inner);
+        xmodem.set_config(config);
+        xmodem
+    }
+}
+
+impl<T: io::Read + io::Write, P: FnMut(Progress)> Xmodem<T, P> {
     /// Returns a new `Xmodem` instance with the internal reader/writer set to
     /// `inner`. The returned instance can be used for both receiving
     /// (downloading) and sending (uploading). The function `f` is used as a
     /// callback to indicate progress throughout the transfer. See the
     /// [`Progress`] enum for more information.
-    pub fn new_with_progress(inner: T, f: ProgressFn) -> Self {
-        Xmodem { packet: 1, started: false, inner, progress: f }
+    pub fn new_with_progress(inner: T, f: P) -> Self {
+        Xmodem {
+            rx: Receiver::new(Mode::Checksum),
+            tx: Transmitter::new(Mode::Checksum),
+            position: Resume::default(),
+            mode: Mode::Checksum,
+            config: XmodemConfig::default(),
+            stats: TransferStats::default(),
+            clock: None,
+            start: None,
+            inner,
+            progress: f
+        }
     }
 
-    /// Reads a single byte from the inner I/O stream. If `abort_on_can` is
-    /// `true`, an error of `ConnectionAborted` is returned if the read byte is
-    /// `CAN`.
+    /// Returns the current error detection mode. After the first packet of a
+    /// transfer, this is the mode agreed upon with the other side.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Sets the error detection mode to `mode`.
+    ///
+    /// When receiving, this is the mode requested from the sender. When
+    /// transmitting in `Mode::Crc`, the mode is switched to whatever the
+    /// receiver requests; in `Mode::Checksum`, requests for CRC are ignored.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.rx.set_mode(mode);
+        self.tx.set_mode(mode);
+    }
+
+    /// Returns the retry, timeout and cancellation policy.
+    pub fn config(&self) -> XmodemConfig {
+        self.config
+    }
+
+    /// Sets the retry, timeout and cancellation policy to `config`.
+    pub fn set_config(&mut self, config: XmodemConfig) {
+        self.config = config;
+        self.rx.set_config(config);
+        self.tx.set_config(config);
+    }
+
+    /// Returns the current position of the transfer: the block following the
+    /// last packet that was acknowledged.
+    pub fn position(&self) -> Resume {
+        self.position
+    }
+
+    /// Resumes a transfer at position `at`. The next packet sent or received
+    /// is block `at.block()`, and the next call to [`Xmodem::read_packet()`] or
+    /// [`Xmodem::write_packet()`] starts with the usual handshake, so the other
+    /// side must be resumed at the same position.
+    pub fn resume(&mut self, at: Resume) {
+        self.position = at;
+        self.set_packet(at.block as u8);
+        self.rx.reset();
+        self.tx.reset();
+    }
+
+    /// Discards input from the inner I/O stream until a read times out or the
+    /// stream ends. Either side resuming after a failure should call this
+    /// first, so what's left of the interrupted exchange isn't read as the
+    /// handshake.
     ///
     /// # Errors
     ///
-    /// Returns an error if reading from the inner stream fails or if
-    /// `abort_on_can` is `true` and the read byte is `CAN`.
-    fn read_byte(&mut self, abort_on_can: bool) -> io::Result<u8> {
-        let mut buf = [0u8; 1];
-        self.inner.read_exact(&mut buf)?;
+    /// Returns an error if reading from the inner stream fails for any other
+    /// reason.
+    pub fn purge(&mut self) -> io::Result<()> {
+        drain(&mut self.inner)
+    }
 
-        let byte = buf[0];
-        if abort_on_can && byte == CAN {
-            return ioerr!(ConnectionAborted, "received CAN");
+    /// Sets the number of the next packet sent or received to `packet`.
+    pub(crate) fn set_packet(&mut self, packet: u8) {
+        self.rx.set_packet(packet);
+        self.tx.set_packet(packet);
+    }
+
+    /// Returns the statistics of the transfer so far.
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Sets the function used to read the current time to `clock`, enabling
+    /// the `elapsed` time and throughput in [`TransferStats`]. The time is
+    /// measured from the start of the first packet's handshake.
+    pub fn set_clock(&mut self, clock: fn() -> Duration) {
+        self.clock = Some(clock);
+    }
+
+    /// Sets the expected total number of bytes to be transferred, reported in
+    /// [`TransferStats::total`].
+    pub fn set_total(&mut self, total: Option<usize>) {
+        self.stats.total = total;
+    }
+
+    /// Clears the statistics and starts measuring the elapsed time anew at the
+    /// next handshake. The expected total is cleared as well.
+    pub fn reset_stats(&mut self) {
+        self.stats = TransferStats::default();
+        self.start = None;
+    }
+
+    /// Starts measuring the elapsed time of the transfer if it isn't already
+    /// being measured.
+    fn start_clock(&mut self) {
+        if let (Some(clock), None) = (self.clock, self.start) {
+            self.start = Some(clock());
         }
+    }
 
-        Ok(byte)
+    /// Records that packet `number` of `len` bytes was acknowledged and
+    /// reports it to the progress callback.
+    fn record_packet(&mut self, number: u8, len: usize) {
+        (self.progress)(Progress::Packet(number));
+        self.position.advance(len);
+        self.stats.packets += 1;
+        self.stats.bytes += len;
+        if let (Some(clock), Some(start)) = (self.clock, self.start) {
+            self.stats.elapsed = clock().checked_sub(start);
+        }
+        (self.progress)(Progress::Transferred(self.stats));
     }
 
-    /// Writes a single byte to the inner I/O stream.
+    /// Records that a packet was rejected and reports it to the progress
+    /// callback.
+    fn record_retry(&mut self) {
+        self.stats.retries += 1;
+        (self.progress)(Progress::NAK);
+    }
+
+    /// Transmits (uploads) all of `data` in packets of `size` followed by end
+    /// of transmission, retrying packets whose checksum fails. Returns the
+    /// number of bytes transmitted, excluding padding zeroes.
+    ///
+    /// Data is sent in packets of `size`. A final chunk shorter than `size` is
+    /// sent as 128-byte packets to keep padding small.
     ///
     /// # Errors
     ///
-    /// Returns an error if writing to the inner stream fails.
-    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
-        self.inner.write_all(&[byte])
+    /// Returns an error if a packet could not be sent. See
+    /// [`Xmodem::write_packet()`]. An error of kind `BrokenPipe` is returned if
+    /// a packet is rejected more than `max_retries` times. If reading from
+    /// `data` fails, the transfer is cancelled (see [`Xmodem::cancel()`]) and
+    /// the error is returned.
+    pub fn send_data<R: io::Read>(&mut self, mut data: R, size: PacketSize) -> io::Result<usize> {
+        let size = size.data_len();
+        let mut packet = [0u8; 1024];
+        let mut written = 0;
+        loop {
+            let n = match data.read_max(&mut packet[..size]) {
+                Ok(n) => n,
+                Err(e) => {
+                    let _ = self.cancel();
+                    return Err(e);
+                }
+            };
+            packet[n..size].iter_mut().for_each(|b| *b = 0);
+
+            if n == 0 {
+                self.write_packet(&[])?;
+                return Ok(written);
+            }
+
+            let block = if n == size { size } else { 128 };
+            let mut offset = 0;
+            'next_packet: while offset < n {
+                for _ in 0..=self.config.max_retries {
+                    match self.write_packet(&packet[offset..offset + block]) {
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                        Ok(_) => {
+                            written += core::cmp::min(block, n - offset);
+                            offset += block;
+                            continue 'next_packet;
+                        }
+                    }
+                }
+
+                return ioerr!(BrokenPipe, "bad transmit");
+            }
+        }
     }
 
-    /// Reads a single byte from the inner I/O stream and compares it to `byte`.
-    /// If the bytes match, the byte is returned as an `Ok`. If they differ and
-    /// the read byte is not `CAN`, an error of `InvalidData` with the message
-    /// `expected` is returned. If they differ and the read byte is `CAN`, an
-    /// error of `ConnectionAborted` is returned. In either case, if they bytes
-    /// differ, a `CAN` byte is written out to the inner stream.
+    /// Receives (downloads) packets into `into` until end of transmission,
+    /// retrying packets whose checksum fails. If `limit` is set, bytes past the
+    /// first `limit` are read but not written. Returns the number of bytes
+    /// received, capped at `limit` if set.
     ///
     /// # Errors
     ///
-    /// Returns an error if reading from the inner stream fails, if the read
-    /// byte was not `byte`, if the read byte was `CAN` and `byte` is not `CAN`,
-    /// or if writing the `CAN` byte failed on byte mismatch.
-    fn expect_byte_or_cancel(&mut self, byte: u8, expected: &'static str) -> io::Result<u8> {
-        let read = self.read_byte(false)?;
-        if read == byte {
-            return Ok(byte);
-        } else {
-            self.write_byte(CAN)?;
-            if read != CAN {
-                return ioerr!(InvalidData, expected);
-            } else {
-                return ioerr!(ConnectionAborted, "received CAN");
+    /// Returns an error if a packet could not be received (see
+    /// [`Xmodem::read_packet()`]) or if writing to `into` fails, in which case
+    /// the transfer is cancelled first (see [`Xmodem::cancel()`]). An error of
+    /// kind `BrokenPipe` is returned if a packet is rejected more than
+    /// `max_retries` times.
+    pub fn receive_data<W: io::Write>(&mut self, mut into: W, limit: Option<usize>) -> io::Result<usize> {
+        let mut packet = [0u8; 1024];
+        let mut received = 0;
+        'next_packet: loop {
+            for _ in 0..=self.config.max_retries {
+                match self.read_packet(&mut packet) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                    Ok(0) => break 'next_packet,
+                    Ok(n) => {
+                        let keep = match limit {
+                            Some(limit) => core::cmp::min(n, limit.saturating_sub(received)),
+                            None => n,
+                        };
+                        if let Err(e) = into.write_all(&packet[..keep]) {
+                            let _ = self.cancel();
+                            return Err(e);
+                        }
+                        received += n;
+                        continue 'next_packet;
+                    }
+                }
             }
-        } 
+
+            return ioerr!(BrokenPipe, "bad receive");
+        }
+
+        Ok(limit.map_or(received, |limit| core::cmp::min(received, limit)))
+    }
+
+    /// Cancels the transfer by sending `CAN CAN` and discarding input until
+    /// the line is quiet, so that the other side fails right away instead of
+    /// waiting for a timeout. The next packet starts with a new handshake.
+    ///
+    /// Draining the line ends when a read times out or the stream ends, so
+    /// reads of the inner stream must not block forever.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to or reading from the inner stream fails.
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.tx.reset();
+        self.rx.cancel();
+        self.write_rx_output()?;
+        self.purge()
     }
 
-    /// Reads a single byte from the inner I/O stream and compares it to `byte`.
-    /// If they differ, an error of `InvalidData` with the message `expected` is
-    /// returned. Otherwise the byte is returned. If `byte` is not `CAN` and the
-    /// read byte is `CAN`, a `ConnectionAborted` error is returned.
+    /// Reads a single byte from the inner I/O stream. If `abort_on_can` is
+    /// `true`, an error of `ConnectionAborted` is returned if the read byte is
+    /// `CAN`. Reads that time out are retried until the configured
+    /// `byte_timeout` has passed.
     ///
     /// # Errors
     ///
-    /// Returns an error if reading from the inner stream fails, or if the read
-    /// byte was not `byte`. If the read byte differed and was `CAN`, an error
-    /// of `ConnectionAborted` is returned. Otherwise, the error kind is
-    /// `InvalidData`.
-    fn expect_byte(&mut self, byte: u8, expected: &'static str) -> io::Result<u8> {
-        let read = self.read_byte(false)?;
-        if read == byte {
-            return Ok(byte);
-        } else {
-            if read != CAN {
-                return ioerr!(InvalidData, expected);
-            } else {
-                return ioerr!(ConnectionAborted, "received CAN");
+    /// Returns an error if reading from the inner stream fails or if
+    /// `abort_on_can` is `true` and the read byte is `CAN`.
+    fn read_byte(&mut self, abort_on_can: bool) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        let deadline = match (self.config.byte_timeout, self.clock) {
+            (Some(timeout), Some(clock)) => Some((clock, clock() + timeout)),
+            _ => None,
+        };
+        loop {
+            match self.inner.read_exact(&mut buf) {
+                Ok(()) => break,
+                Err(e) => match deadline {
+                    Some((clock, deadline)) if is_timeout(&e) && clock() < deadline => continue,
+                    _ => return Err(e),
+                }
             }
         }
+
+        let byte = buf[0];
+        if abort_on_can && byte == CAN {
+            return ioerr!(ConnectionAborted, "received CAN");
+        }
+
+        Ok(byte)
+    }
+
+    /// Writes out the bytes queued by the receiver state machine.
+    fn write_rx_output(&mut self) -> io::Result<()> {
+        let rx = &mut self.rx;
+        write_output(&mut self.inner, || rx.next_output())
+    }
+
+    /// Writes out the bytes queued by the transmitter state machine.
+    fn write_tx_output(&mut self) -> io::Result<()> {
+        let tx = &mut self.tx;
+        write_output(&mut self.inner, || tx.next_output())
     }
 
     /// Reads (downloads) a single packet from the inner stream using the XMODEM
-    /// protocol. On success, returns the number of bytes read (always 128).
+    /// protocol. On success, returns the number of bytes read: 128 for an `SOH`
+    /// packet, 1024 for an XMODEM-1K `STX` packet, or 0 at end of transmission.
+    ///
+    /// The first call requests the packet with `'C'` in CRC mode and with
+    /// `NAK` in checksum mode. See [`Xmodem::set_mode()`].
+    ///
+    /// A packet numbered like the previous one is a retransmission by a sender
+    /// that missed our `ACK`; it is acknowledged again and discarded, and the
+    /// next packet is read instead. Packet numbers wrap around after 255.
     ///
     /// The progress callback is called with `Progress::Started` when reception
     /// for the first packet has started and subsequently with
-    /// `Progress::Packet` when a packet is received successfully.
+    /// `Progress::Packet` and `Progress::Transferred` when a packet is received
+    /// successfully, `Progress::NAK` when it is rejected, or
+    /// `Progress::Duplicate` when a duplicate is discarded.
+    ///
+    /// This is a blocking driver for a [`Receiver`]; use one directly to
+    /// receive without blocking.
     ///
     /// # Errors
     ///
@@ -232,62 +766,77 @@ impl<T: io::Read + io::Write> Xmodem<T> {
     /// point. Also returns an error if the XMODEM protocol indicates an error.
     /// In particular, an `InvalidData` error is returned when:
     ///
-    ///   * The sender's first byte for a packet isn't `EOT` or `SOH`.
+    ///   * The sender's first byte for a packet isn't `EOT`, `SOH` or `STX`.
     ///   * The sender doesn't send a second `EOT` after the first.
-    ///   * The received packet numbers don't match the expected values.
+    ///   * The received packet number is neither the expected one nor the
+    ///     previous one, or its complement doesn't match.
     ///
-    /// An error of kind `Interrupted` is returned if a packet checksum fails.
+    /// An error of kind `Interrupted` is returned if a packet checksum or CRC
+    /// fails.
     ///
     /// An error of kind `ConnectionAborted` is returned if a `CAN` byte is
     /// received when not expected.
     ///
-    /// An error of kind `UnexpectedEof` is returned if `buf.len() < 128`.
+    /// An error of kind `UnexpectedEof` is returned if `buf.len() < 128`, or if
+    /// `buf.len() < 1024` and the sender starts an `STX` packet. In the latter
+    /// case, a `CAN` byte is written out to the inner stream.
     pub fn read_packet(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if buf.len() < 128 {
             return ioerr!(UnexpectedEof, "buf len too short");
         }
 
-        if !self.started {
-            self.write_byte(NAK)?;
-            self.flush()?;
-            self.started = true;
+        self.rx.set_accept_1k(buf.len() >= 1024);
+        if !self.rx.started() {
+            self.start_clock();
             (self.progress)(Progress::Started);
+            self.rx.start();
         }
 
-        let read = self.read_byte(true)?;
-        if read == EOT {
-            self.write_byte(NAK)?;
-            self.flush()?;
-            let read2 = self.read_byte(false)?;
-            if read2 == EOT {
-                self.write_byte(ACK)?;
-                self.started = false;
-                return Ok(0);
-            } else {
-                self.write_byte(CAN)?;
-                return ioerr!(InvalidData, "failed eot");
+        loop {
+            self.write_rx_output()?;
+            let byte = match self.read_byte(false) {
+                Ok(byte) => byte,
+                Err(e) => {
+                    // Only the handshake is retried; a timeout within a
+                    // transfer fails it.
+                    if is_timeout(&e) && self.rx.handshaking() && self.rx.timeout().is_ok() {
+                        self.mode = self.rx.mode();
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+
+            let event = self.rx.feed(byte);
+            self.mode = self.rx.mode();
+            match event {
+                Ok(None) | Ok(Some(Event::Started)) => continue,
+                Ok(Some(Event::Packet { number, len })) => {
+                    self.write_rx_output()?;
+                    buf[..len].copy_from_slice(self.rx.data());
+                    self.record_packet(number, len);
+                    return Ok(len);
+                }
+                Ok(Some(Event::Duplicate(number))) => {
+                    (self.progress)(Progress::Duplicate(number));
+                }
+                Ok(Some(Event::Rejected)) => {
+                    if self.config.purge_before_nak {
+                        self.purge()?;
+                    }
+                    self.write_rx_output()?;
+                    self.record_retry();
+                    return ioerr!(Interrupted, "invalid checksum");
+                }
+                Ok(Some(Event::End)) => {
+                    self.write_rx_output()?;
+                    return Ok(0);
+                }
+                Err(e) => {
+                    self.write_rx_output()?;
+                    return Err(e);
+                }
             }
-        } else if read != SOH {
-            self.write_byte(CAN)?;
-            return ioerr!(InvalidData, "first byte not EOT or SOH");
-        }
-
-        self.expect_byte_or_cancel(self.packet, "packet number")?;
-        self.expect_byte_or_cancel(255 - self.packet, "1s cmpl packet number")?;
-
-        for i in 0..128 {
-            buf[i] = self.read_byte(false)?;
-        }
-        let checksum = get_checksum(buf);
-        if self.read_byte(false)? != checksum {
-            self.write_byte(NAK)?;
-            (self.progress)(Progress::NAK);
-            return ioerr!(Interrupted, "invalid checksum");
-        } else {
-            self.write_byte(ACK)?; 
-            (self.progress)(Progress::Packet(self.packet));
-            self.packet += 1;
-            return Ok(128);
         }
     }
 
@@ -297,10 +846,21 @@ impl<T: io::Read + io::Write> Xmodem<T> {
     /// transmission is complete. On success, returns the number of bytes
     /// written.
     ///
+    /// If `buf.len() >= 1024`, the first 1024 bytes are sent as an XMODEM-1K
+    /// `STX` packet. Otherwise, the first 128 bytes are sent as an `SOH`
+    /// packet.
+    ///
+    /// The first call waits for the receiver's `NAK` or `'C'` and switches to
+    /// checksum or CRC mode accordingly. See [`Xmodem::set_mode()`].
+    ///
     /// The progress callback is called with `Progress::Waiting` before waiting
     /// for the receiver's `NAK`, `Progress::Started` when transmission of the
-    /// first packet has started and subsequently with `Progress::Packet` when a
-    /// packet is sent successfully.
+    /// first packet has started and subsequently with `Progress::Packet` and
+    /// `Progress::Transferred` when a packet is sent successfully or
+    /// `Progress::NAK` when the receiver rejects it.
+    ///
+    /// This is a blocking driver for a [`Transmitter`]; use one directly to
+    /// transmit without blocking.
     ///
     /// # Errors
     ///
@@ -308,7 +868,7 @@ impl<T: io::Read + io::Write> Xmodem<T> {
     /// point. Also returns an error if the XMODEM protocol indicates an error.
     /// In particular, an `InvalidData` error is returned when:
     ///
-    ///   * The receiver's first byte isn't a `NAK`.
+    ///   * The receiver's first byte isn't a `NAK` or `'C'`.
     ///   * The receiver doesn't respond with a `NAK` to the first `EOT`.
     ///   * The receiver doesn't respond with an `ACK` to the second `EOT`.
     ///   * The receiver responds to a complete packet with something besides
@@ -317,68 +877,70 @@ impl<T: io::Read + io::Write> Xmodem<T> {
     /// An error of kind `UnexpectedEof` is returned if `buf.len() < 128 &&
     /// buf.len() != 0`.
     ///
-    /// An error of kind `ConnectionAborted` is returned if a `CAN` byte is
-    /// received when not expected.
+    /// An error of kind `ConnectionAborted` is returned if the receiver sends
+    /// `CAN` instead of a response.
     ///
-    /// An error of kind `Interrupted` is returned if a packet checksum fails.
+    /// An error of kind `Interrupted` is returned if the receiver rejects the
+    /// packet or doesn't respond before reading from the inner stream times
+    /// out. The packet should be sent again; see [`Xmodem::send_data()`].
     pub fn write_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if !self.started {
+        if !self.tx.started() {
+            self.start_clock();
             (self.progress)(Progress::Waiting);
-            let byte = self.read_byte(true)?;
-            if byte != NAK {
-                return ioerr!(InvalidData, "first byte not NAK");
-            } else {
-                (self.progress)(Progress::Started);
-                self.started = true;
+            self.tx.start();
+            loop {
+                let byte = match self.read_byte(false) {
+                    Ok(byte) => byte,
+                    Err(e) => {
+                        if is_timeout(&e) && self.tx.timeout().is_ok() {
+                            continue;
+                        }
+                        return Err(e);
+                    }
+                };
+
+                let event = self.tx.feed(byte);
+                self.mode = self.tx.mode();
+                if let Some(Event::Started) = event? {
+                    break;
+                }
             }
+            (self.progress)(Progress::Started);
         }
 
         if buf.len() == 0 {
-            self.write_byte(EOT)?;
-            self.flush()?;
-            if self.read_byte(false)? != NAK {
-                return ioerr!(InvalidData, "missing NAK for EOT");
-            } 
-            self.write_byte(EOT)?;
-            self.flush()?;
-            if self.read_byte(false)? != ACK {
-                return ioerr!(InvalidData, "missing ACK for EOT");
+            self.tx.finish()?;
+            loop {
+                self.write_tx_output()?;
+                let byte = self.read_byte(false)?;
+                if let Some(Event::End) = self.tx.feed(byte)? {
+                    break;
+                }
             }
-            self.started = false;
             (self.progress)(Progress::Waiting);
             return Ok(0);
-        } else if buf.len() != 0 && buf.len() < 128 {
-            return ioerr!(UnexpectedEof, "buf len too short");
         }
 
-        self.write_byte(SOH)?;
-        self.flush()?;
-        self.write_byte(self.packet)?;
-        self.flush()?;
-        self.write_byte(255 - self.packet)?;
-        self.flush()?;
-
-        for i in 0..1 + 10 {
-            for i in 0..128 {
-                self.write_byte(buf[i])?;
-                self.flush()?;
-            }
-            let checksum = get_checksum(buf);
-            self.write_byte(checksum)?;
-            self.flush()?;
-
-            let read = self.read_byte(false)?;
-            if read == NAK {
-                continue;
-            } else if read == ACK {
-                (self.progress)(Progress::Packet(self.packet));
-                self.packet += 1;
-                return Ok(128);
-            } else {
-                return ioerr!(InvalidData, "invalid byte");
+        self.tx.send(buf)?;
+        loop {
+            self.write_tx_output()?;
+            let event = match self.read_byte(false) {
+                Ok(byte) => self.tx.feed(byte)?,
+                Err(ref e) if is_timeout(e) => self.tx.timeout()?,
+                Err(e) => return Err(e),
+            };
+            match event {
+                Some(Event::Packet { number, len }) => {
+                    self.record_packet(number, len);
+                    return Ok(len);
+                }
+                Some(Event::Rejected) => {
+                    self.record_retry();
+                    return ioerr!(Interrupted, "packet rejected or unanswered");
+                }
+                _ => continue,
             }
         }
-        return ioerr!(Interrupted, "invalid checksum");
     }
 
     /// Flush this output stream, ensuring that all intermediately buffered
@@ -0,0 +1,154 @@
+use shim::io;
+
+/// A fault injected into a byte written through a [`Lossy`] channel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// The byte is lost.
+    Drop,
+    /// The bits of the byte set in the mask are flipped.
+    Flip(u8),
+    /// The byte is delivered twice.
+    Duplicate,
+}
+
+/// A xorshift64* generator: small, and reproducible from its seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // A zero state would only ever yield zeroes.
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+    }
+
+    /// Returns `true` with a probability of `per_mille` / 1000.
+    pub fn chance(&mut self, per_mille: u32) -> bool {
+        self.next_u32() % 1000 < per_mille
+    }
+}
+
+/// An `io::Read + io::Write` adapter that injects faults into the bytes
+/// written through it, simulating a noisy line. Reads pass through untouched;
+/// wrap both ends of a channel to disturb both directions.
+///
+/// Faults are either scheduled at a byte offset of the written stream or
+/// drawn at random from a seeded [`Rng`], so every run with the same seed and
+/// schedule injects the same faults.
+pub struct Lossy<T> {
+    inner: T,
+    rng: Rng,
+    drops: u32,
+    flips: u32,
+    duplicates: u32,
+    schedule: Vec<(usize, Fault)>,
+    written: usize,
+    injected: Vec<(usize, Fault)>,
+}
+
+impl<T> Lossy<T> {
+    /// Returns a channel writing to `inner` without faults, drawing random
+    /// faults from an RNG seeded with `seed`.
+    pub fn new(inner: T, seed: u64) -> Lossy<T> {
+        Lossy {
+            inner,
+            rng: Rng::new(seed),
+            drops: 0,
+            flips: 0,
+            duplicates: 0,
+            schedule: vec![],
+            written: 0,
+            injected: vec![],
+        }
+    }
+
+    /// Injects `fault` into the byte at `offset` of the written stream.
+    pub fn at(mut self, offset: usize, fault: Fault) -> Lossy<T> {
+        self.schedule.push((offset, fault));
+        self
+    }
+
+    /// Drops bytes at random, `per_mille` in a thousand.
+    pub fn drops(mut self, per_mille: u32) -> Lossy<T> {
+        self.drops = per_mille;
+        self
+    }
+
+    /// Flips a random bit of bytes at random, `per_mille` in a thousand.
+    pub fn flips(mut self, per_mille: u32) -> Lossy<T> {
+        self.flips = per_mille;
+        self
+    }
+
+    /// Duplicates bytes at random, `per_mille` in a thousand.
+    pub fn duplicates(mut self, per_mille: u32) -> Lossy<T> {
+        self.duplicates = per_mille;
+        self
+    }
+
+    /// Returns the faults injected so far with their offsets.
+    pub fn injected(&self) -> &[(usize, Fault)] {
+        &self.injected
+    }
+
+    /// Returns the inner reader/writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns the fault to inject at `offset`, if any.
+    fn fault(&mut self, offset: usize) -> Option<Fault> {
+        if let Some(&(_, fault)) = self.schedule.iter().find(|(at, _)| *at == offset) {
+            return Some(fault);
+        }
+
+        if self.rng.chance(self.drops) {
+            Some(Fault::Drop)
+        } else if self.rng.chance(self.flips) {
+            Some(Fault::Flip(1 << (self.rng.next_u32() % 8)))
+        } else if self.rng.chance(self.duplicates) {
+            Some(Fault::Duplicate)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: io::Read> io::Read for Lossy<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: io::Write> io::Write for Lossy<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() * 2);
+        for &byte in buf {
+            let offset = self.written;
+            self.written += 1;
+            match self.fault(offset) {
+                None => out.push(byte),
+                Some(fault) => {
+                    self.injected.push((offset, fault));
+                    match fault {
+                        Fault::Drop => {}
+                        Fault::Flip(mask) => out.push(byte ^ mask),
+                        Fault::Duplicate => out.extend_from_slice(&[byte, byte]),
+                    }
+                }
+            }
+        }
+
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
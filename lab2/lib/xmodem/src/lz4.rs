@@ -0,0 +1,207 @@
+//! LZ4 block compression of boot images.
+//!
+//! A compressed image is the image's length as a little-endian `u32`
+//! followed by an LZ4 block. It's decompressed in place, in the buffer it was
+//! received in, so the bootloader needs no memory besides the image's own.
+
+use crate::image::ImageError;
+
+/// Length of the shortest match.
+const MIN_MATCH: usize = 4;
+
+/// The last match must start this many bytes before the end of the input.
+const MF_LIMIT: usize = 12;
+
+/// The last bytes of the input are always literals.
+const LAST_LITERALS: usize = 5;
+
+/// Number of bits of the hashes indexing the compressor's table.
+const HASH_BITS: u32 = 12;
+
+/// Returns the largest length of `len` bytes compressed by [`compress()`].
+pub fn max_compressed_len(len: usize) -> usize {
+    4 + len + len / 255 + 16
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Appends to a fixed buffer, failing once the buffer is full.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn push(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.len)? = byte;
+        self.len += 1;
+        Some(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buf.get_mut(self.len..self.len + bytes.len())?.copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    /// Appends the part of a length beyond a token's 4 bits.
+    fn push_length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+        self.push(len as u8)
+    }
+
+    /// Appends a sequence of `literals` followed by a match of `len` bytes
+    /// `offset` bytes back, or by nothing if `len` is 0.
+    fn sequence(&mut self, literals: &[u8], offset: usize, len: usize) -> Option<()> {
+        let match_len = len.saturating_sub(MIN_MATCH);
+        let token = (literals.len().min(15) << 4 | match_len.min(15)) as u8;
+        self.push(token)?;
+        if literals.len() >= 15 {
+            self.push_length(literals.len() - 15)?;
+        }
+        self.extend(literals)?;
+        if len == 0 {
+            return Some(());
+        }
+
+        self.extend(&(offset as u16).to_le_bytes())?;
+        if match_len >= 15 {
+            self.push_length(match_len - 15)?;
+        }
+        Some(())
+    }
+}
+
+/// Compresses `input` into `output`. Returns the compressed length, or `None`
+/// if `output` is too short; [`max_compressed_len()`] always suffices.
+///
+/// # Panics
+///
+/// Panics if `input` is 4GiB or longer.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    assert!(input.len() <= u32::MAX as usize, "input too large");
+    let mut writer = Writer { buf: output, len: 0 };
+    writer.extend(&(input.len() as u32).to_le_bytes())?;
+
+    let mut table = [0u32; 1 << HASH_BITS];
+    let (mut anchor, mut pos) = (0, 0);
+    while pos + MF_LIMIT < input.len() {
+        let sequence = u32_at(input, pos);
+        let slot = &mut table[hash(sequence)];
+        let candidate = *slot as usize;
+        *slot = pos as u32;
+        if candidate >= pos || pos - candidate > 0xffff || u32_at(input, candidate) != sequence {
+            pos += 1;
+            continue;
+        }
+
+        let max = input.len() - LAST_LITERALS - pos;
+        let mut len = MIN_MATCH;
+        while len < max && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+
+        writer.sequence(&input[anchor..pos], pos - candidate, len)?;
+        pos += len;
+        anchor = pos;
+    }
+
+    writer.sequence(&input[anchor..], 0, 0)?;
+    Some(writer.len)
+}
+
+/// Reads the part of a length beyond a token's 4 bits from `buf` at `*at`,
+/// before `end`.
+fn read_length(buf: &[u8], at: &mut usize, end: usize) -> Result<usize, ImageError> {
+    let mut len = 0usize;
+    loop {
+        if *at >= end {
+            return Err(ImageError::BadCompression);
+        }
+        let byte = buf[*at];
+        *at += 1;
+        len = len.checked_add(byte as usize).ok_or(ImageError::BadCompression)?;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Decompresses the compressed image in `buf[start..start + len]` to the
+/// start of `buf`. Returns the length of the image.
+///
+/// The image is written ahead of the compressed data that's still to be
+/// read, so `start` should leave room for the image to grow: with the
+/// compressed data at the end of `buf`, decompression succeeds unless the
+/// image barely fits in `buf`.
+///
+/// # Errors
+///
+/// Returns `TooLarge` if the image doesn't fit in `buf`, `NoRoom` if it
+/// would overwrite compressed data that's still to be read, and
+/// `BadCompression` if the compressed data is malformed.
+pub fn decompress_in_place(buf: &mut [u8], start: usize, len: usize) -> Result<usize, ImageError> {
+    let end = start.checked_add(len).filter(|&end| end <= buf.len() && len >= 4)
+        .ok_or(ImageError::BadCompression)?;
+    let size = u32_at(buf, start) as usize;
+    if size > buf.len() {
+        return Err(ImageError::TooLarge { len: size, max: buf.len() });
+    }
+
+    let (mut input, mut out) = (start + 4, 0);
+    loop {
+        if input >= end {
+            return Err(ImageError::BadCompression);
+        }
+        let token = buf[input];
+        input += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(buf, &mut input, end)?;
+        }
+        if literals > end - input || out + literals > size {
+            return Err(ImageError::BadCompression);
+        }
+        buf.copy_within(input..input + literals, out);
+        input += literals;
+        out += literals;
+        if input == end {
+            break;
+        }
+
+        if end - input < 2 {
+            return Err(ImageError::BadCompression);
+        }
+        let offset = u16::from_le_bytes([buf[input], buf[input + 1]]) as usize;
+        input += 2;
+
+        let mut len = (token & 0xf) as usize + MIN_MATCH;
+        if len == 15 + MIN_MATCH {
+            len += read_length(buf, &mut input, end)?;
+        }
+        if offset == 0 || offset > out || len > size - out {
+            return Err(ImageError::BadCompression);
+        } else if out + len > input {
+            return Err(ImageError::NoRoom);
+        }
+        for i in out..out + len {
+            buf[i] = buf[i - offset];
+        }
+        out += len;
+    }
+
+    if out != size {
+        return Err(ImageError::BadCompression);
+    }
+    Ok(size)
+}
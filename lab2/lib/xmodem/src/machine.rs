@@ -0,0 +1,644 @@
+use shim::io;
+use shim::ioerr;
+
+use crate::{get_checksum, get_crc, Mode, XmodemConfig, ACK, CAN, CRC, CRC_ATTEMPTS, EOT, NAK, SOH, STX};
+
+/// Something that happened in a transfer driven by a [`Receiver`] or a
+/// [`Transmitter`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The receiver answered the transmitter's handshake. Packets may now be
+    /// sent. Only emitted by a [`Transmitter`].
+    Started,
+    /// Packet `number` carrying `len` bytes was acknowledged.
+    Packet { number: u8, len: usize },
+    /// A duplicate of packet `.0` was received, acknowledged again and
+    /// discarded. Only emitted by a [`Receiver`].
+    Duplicate(u8),
+    /// A packet was rejected, or its response timed out. A receiver asks for
+    /// it again; a transmitter sends it again.
+    Rejected,
+    /// The end of transmission was acknowledged.
+    End,
+}
+
+/// A small FIFO of control bytes waiting to be sent.
+struct Output {
+    bytes: [u8; 4],
+    head: usize,
+    len: usize,
+}
+
+impl Output {
+    fn new() -> Output {
+        Output { bytes: [0; 4], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.bytes.len() {
+            self.bytes[(self.head + self.len) % self.bytes.len()] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % self.bytes.len();
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Queues the cancel sequence: one `CAN`, or two if `double_can` is set.
+    fn cancel(&mut self, double_can: bool) {
+        self.push(CAN);
+        if double_can {
+            self.push(CAN);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RxState {
+    Start,
+    Eot,
+    Number,
+    Complement,
+    Data,
+    Check,
+    CheckLow,
+}
+
+/// A poll-based XMODEM receiver that does no I/O and no allocation.
+///
+/// The receiver is driven by its user: bytes read from the line are passed to
+/// [`Receiver::feed()`], bytes returned by [`Receiver::next_output()`] are
+/// written to the line, and [`Receiver::timeout()`] is called when the line
+/// has been quiet for too long. This lets a transfer run in the background,
+/// from an interrupt handler or a timer, while the caller does other work.
+/// [`Xmodem::read_packet()`](crate::Xmodem::read_packet) is built on it.
+///
+/// Packets are received into an internal buffer, available from
+/// [`Receiver::data()`] once [`Event::Packet`] has been returned.
+pub struct Receiver {
+    mode: Mode,
+    config: XmodemConfig,
+    packet: u8,
+    started: bool,
+    answered: bool,
+    attempts: usize,
+    accept_1k: bool,
+    state: RxState,
+    number: u8,
+    len: usize,
+    pos: usize,
+    check: u8,
+    buf: [u8; 1024],
+    out: Output,
+}
+
+impl Receiver {
+    /// Returns a receiver that requests packets in `mode`, expecting packet 1
+    /// first.
+    pub fn new(mode: Mode) -> Receiver {
+        Receiver {
+            mode,
+            config: XmodemConfig::default(),
+            packet: 1,
+            started: false,
+            answered: false,
+            attempts: 0,
+            accept_1k: true,
+            state: RxState::Start,
+            number: 0,
+            len: 0,
+            pos: 0,
+            check: 0,
+            buf: [0; 1024],
+            out: Output::new(),
+        }
+    }
+
+    /// Returns the current error detection mode. Once the sender has answered
+    /// the handshake, this is the mode agreed upon with it.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Sets the error detection mode requested from the sender to `mode`.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Sets the handshake and cancellation policy to `config`. Its
+    /// `handshake_retries` and `double_can` apply.
+    pub fn set_config(&mut self, config: XmodemConfig) {
+        self.config = config;
+    }
+
+    /// Sets whether XMODEM-1K `STX` packets are accepted. If not, such a packet
+    /// cancels the transfer. Accepted by default.
+    pub fn set_accept_1k(&mut self, accept: bool) {
+        self.accept_1k = accept;
+    }
+
+    /// Returns the number of the next packet expected.
+    pub fn packet(&self) -> u8 {
+        self.packet
+    }
+
+    /// Sets the number of the next packet expected to `packet`.
+    pub fn set_packet(&mut self, packet: u8) {
+        self.packet = packet;
+    }
+
+    /// Returns `true` if the first packet has been requested and the transfer
+    /// hasn't ended since.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// Returns `true` while the receiver waits for the sender to answer its
+    /// request for the first packet.
+    pub fn handshaking(&self) -> bool {
+        self.started && !self.answered
+    }
+
+    /// Starts a transfer by requesting the first packet: with `'C'` in CRC mode
+    /// and with `NAK` in checksum mode.
+    pub fn start(&mut self) {
+        self.reset();
+        self.started = true;
+        self.request();
+    }
+
+    /// Abandons the current transfer without telling the sender. Queued
+    /// output is discarded. The packet number is kept.
+    pub fn reset(&mut self) {
+        self.started = false;
+        self.answered = false;
+        self.attempts = 0;
+        self.state = RxState::Start;
+        self.out.clear();
+    }
+
+    /// Cancels the transfer by queueing `CAN CAN`. See [`Receiver::reset()`].
+    pub fn cancel(&mut self) {
+        self.reset();
+        self.out.push(CAN);
+        self.out.push(CAN);
+    }
+
+    /// Returns the data of the packet last reported by [`Event::Packet`].
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns the next byte to send to the sender, if any.
+    pub fn next_output(&mut self) -> Option<u8> {
+        self.out.pop()
+    }
+
+    /// Handles the line going quiet. While waiting for the sender to answer the
+    /// handshake, the request is repeated up to `handshake_retries` times,
+    /// falling back to checksum mode after at most 3 unanswered `'C'`s. Later,
+    /// the partial packet is discarded and rejected with `NAK`, returning
+    /// `Event::Rejected`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `TimedOut` if the handshake retries ran out.
+    pub fn timeout(&mut self) -> io::Result<Option<Event>> {
+        if !self.started {
+            return Ok(None);
+        } else if self.answered {
+            self.state = RxState::Start;
+            self.out.push(NAK);
+            return Ok(Some(Event::Rejected));
+        }
+
+        let retries = self.config.handshake_retries;
+        if self.attempts >= retries {
+            return ioerr!(TimedOut, "no response to handshake");
+        }
+
+        self.attempts += 1;
+        if self.attempts >= core::cmp::min(CRC_ATTEMPTS, retries) {
+            self.mode = Mode::Checksum;
+        }
+        self.request();
+        Ok(None)
+    }
+
+    /// Handles `byte` received from the sender. Returns the event it completed,
+    /// if any. Responses to the sender are queued; see
+    /// [`Receiver::next_output()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the XMODEM protocol indicates an error, with the
+    /// kinds described in [`Xmodem::read_packet()`](crate::Xmodem::read_packet).
+    /// The cancel sequence is queued unless the sender cancelled.
+    pub fn feed(&mut self, byte: u8) -> io::Result<Option<Event>> {
+        if self.started {
+            self.answered = true;
+        }
+
+        match self.state {
+            RxState::Start => match byte {
+                EOT => {
+                    self.out.push(NAK);
+                    self.state = RxState::Eot;
+                }
+                SOH => self.begin(128),
+                STX if self.accept_1k => self.begin(1024),
+                STX => {
+                    self.out.cancel(self.config.double_can);
+                    return ioerr!(UnexpectedEof, "buf len too short for 1K packet");
+                }
+                CAN => return ioerr!(ConnectionAborted, "received CAN"),
+                _ => {
+                    self.out.cancel(self.config.double_can);
+                    return ioerr!(InvalidData, "first byte not EOT, SOH or STX");
+                }
+            },
+            RxState::Eot => {
+                self.state = RxState::Start;
+                if byte != EOT {
+                    self.out.cancel(self.config.double_can);
+                    return ioerr!(InvalidData, "failed eot");
+                }
+
+                self.out.push(ACK);
+                self.started = false;
+                self.answered = false;
+                return Ok(Some(Event::End));
+            }
+            RxState::Number => {
+                let duplicate = byte == self.packet.wrapping_sub(1);
+                if byte != self.packet && !duplicate {
+                    self.state = RxState::Start;
+                    self.out.cancel(self.config.double_can);
+                    if byte == CAN {
+                        return ioerr!(ConnectionAborted, "received CAN");
+                    }
+                    return ioerr!(InvalidData, "packet number");
+                }
+
+                self.number = byte;
+                self.state = RxState::Complement;
+            }
+            RxState::Complement => {
+                if byte != 255 - self.number {
+                    self.state = RxState::Start;
+                    self.out.cancel(self.config.double_can);
+                    if byte == CAN {
+                        return ioerr!(ConnectionAborted, "received CAN");
+                    }
+                    return ioerr!(InvalidData, "1s cmpl packet number");
+                }
+
+                self.state = RxState::Data;
+            }
+            RxState::Data => {
+                self.buf[self.pos] = byte;
+                self.pos += 1;
+                if self.pos == self.len {
+                    self.state = RxState::Check;
+                }
+            }
+            RxState::Check => match self.mode {
+                Mode::Checksum => return Ok(Some(self.end(byte == get_checksum(self.data())))),
+                Mode::Crc => {
+                    self.check = byte;
+                    self.state = RxState::CheckLow;
+                }
+            },
+            RxState::CheckLow => {
+                let crc = u16::from_be_bytes([self.check, byte]);
+                return Ok(Some(self.end(crc == get_crc(self.data()))));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Queues the request for the first packet in the current mode.
+    fn request(&mut self) {
+        self.out.push(if self.mode == Mode::Crc { CRC } else { NAK });
+    }
+
+    /// Starts reading a packet of `len` bytes.
+    fn begin(&mut self, len: usize) {
+        self.len = len;
+        self.pos = 0;
+        self.state = RxState::Number;
+    }
+
+    /// Answers a complete packet whose checksum was `valid`.
+    fn end(&mut self, valid: bool) -> Event {
+        self.state = RxState::Start;
+        if !valid {
+            self.out.push(NAK);
+            Event::Rejected
+        } else if self.number != self.packet {
+            // The sender missed our ACK and sent the packet again.
+            self.out.push(ACK);
+            Event::Duplicate(self.number)
+        } else {
+            self.out.push(ACK);
+            self.packet = self.packet.wrapping_add(1);
+            Event::Packet { number: self.number, len: self.len }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    Handshake,
+    Packet(usize),
+    Response,
+    EotNak,
+    EotAck,
+}
+
+/// A poll-based XMODEM transmitter that does no I/O and no allocation.
+///
+/// Like a [`Receiver`], the transmitter is driven by its user: bytes read from
+/// the line are passed to [`Transmitter::feed()`], bytes returned by
+/// [`Transmitter::next_output()`] are written to the line, and
+/// [`Transmitter::timeout()`] is called when the line has been quiet for too
+/// long. [`Xmodem::write_packet()`](crate::Xmodem::write_packet) is built on
+/// it.
+///
+/// A transfer starts with [`Transmitter::start()`]. Once [`Event::Started`] has
+/// been returned, each packet is sent with [`Transmitter::send()`] and resent
+/// automatically until it's acknowledged, and [`Transmitter::finish()`] ends
+/// the transfer.
+pub struct Transmitter {
+    mode: Mode,
+    config: XmodemConfig,
+    packet: u8,
+    started: bool,
+    attempts: usize,
+    state: TxState,
+    header: u8,
+    len: usize,
+    check: u16,
+    buf: [u8; 1024],
+    out: Output,
+}
+
+impl Transmitter {
+    /// Returns a transmitter in `mode` whose first packet is numbered 1. See
+    /// [`Transmitter::set_mode()`].
+    pub fn new(mode: Mode) -> Transmitter {
+        Transmitter {
+            mode,
+            config: XmodemConfig::default(),
+            packet: 1,
+            started: false,
+            attempts: 0,
+            state: TxState::Idle,
+            header: SOH,
+            len: 0,
+            check: 0,
+            buf: [0; 1024],
+            out: Output::new(),
+        }
+    }
+
+    /// Returns the current error detection mode. Once the receiver has
+    /// answered the handshake, this is the mode agreed upon with it.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Sets the error detection mode to `mode`. In `Mode::Crc`, the mode is
+    /// switched to whatever the receiver requests; in `Mode::Checksum`,
+    /// requests for CRC are ignored.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Sets the handshake policy to `config`. Its `handshake_retries` apply.
+    pub fn set_config(&mut self, config: XmodemConfig) {
+        self.config = config;
+    }
+
+    /// Returns the number of the next packet sent.
+    pub fn packet(&self) -> u8 {
+        self.packet
+    }
+
+    /// Sets the number of the next packet sent to `packet`.
+    pub fn set_packet(&mut self, packet: u8) {
+        self.packet = packet;
+    }
+
+    /// Returns `true` if the receiver has answered the handshake and the
+    /// transfer hasn't ended since.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// Starts a transfer by waiting for the receiver's `NAK` or `'C'`.
+    pub fn start(&mut self) {
+        self.reset();
+        self.state = TxState::Handshake;
+    }
+
+    /// Abandons the current transfer without telling the receiver. Queued
+    /// output is discarded. The packet number is kept.
+    pub fn reset(&mut self) {
+        self.started = false;
+        self.attempts = 0;
+        self.state = TxState::Idle;
+        self.out.clear();
+    }
+
+    /// Cancels the transfer by queueing `CAN CAN`. See
+    /// [`Transmitter::reset()`].
+    pub fn cancel(&mut self) {
+        self.reset();
+        self.out.push(CAN);
+        self.out.push(CAN);
+    }
+
+    /// Starts sending a packet. If `buf.len() >= 1024`, the first 1024 bytes
+    /// are sent as an XMODEM-1K `STX` packet. Otherwise, the first 128 bytes
+    /// are sent as an `SOH` packet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if the receiver hasn't answered
+    /// the handshake yet, and of kind `UnexpectedEof` if `buf.len() < 128`.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        if !self.started {
+            return ioerr!(InvalidInput, "transfer not started");
+        } else if buf.len() < 128 {
+            return ioerr!(UnexpectedEof, "buf len too short");
+        }
+
+        let (header, len) = if buf.len() >= 1024 { (STX, 1024) } else { (SOH, 128) };
+        self.header = header;
+        self.len = len;
+        self.buf[..len].copy_from_slice(&buf[..len]);
+        self.check = match self.mode {
+            Mode::Checksum => get_checksum(&buf[..len]) as u16,
+            Mode::Crc => get_crc(&buf[..len]),
+        };
+        self.state = TxState::Packet(0);
+        Ok(())
+    }
+
+    /// Ends the transfer by sending `EOT`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if the receiver hasn't answered
+    /// the handshake yet.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.started {
+            return ioerr!(InvalidInput, "transfer not started");
+        }
+
+        self.out.push(EOT);
+        self.state = TxState::EotNak;
+        Ok(())
+    }
+
+    /// Returns the next byte to send to the receiver, if any.
+    pub fn next_output(&mut self) -> Option<u8> {
+        if let Some(byte) = self.out.pop() {
+            return Some(byte);
+        }
+
+        let pos = match self.state {
+            TxState::Packet(pos) => pos,
+            _ => return None,
+        };
+        let byte = match pos {
+            0 => self.header,
+            1 => self.packet,
+            2 => 255 - self.packet,
+            i if i < 3 + self.len => self.buf[i - 3],
+            _ => match self.mode {
+                Mode::Checksum => self.check as u8,
+                Mode::Crc => self.check.to_be_bytes()[pos - 3 - self.len],
+            },
+        };
+
+        let trailer = if self.mode == Mode::Crc { 2 } else { 1 };
+        self.state = if pos + 1 == 3 + self.len + trailer {
+            TxState::Response
+        } else {
+            TxState::Packet(pos + 1)
+        };
+        Some(byte)
+    }
+
+    /// Handles the line going quiet. While waiting for the handshake, waiting
+    /// continues up to `handshake_retries` times. While waiting for the
+    /// response to a packet, the packet is sent again and `Event::Rejected` is
+    /// returned: the receiver missed the packet, or we missed its `ACK`, and a
+    /// receiver discards a packet it already acknowledged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `TimedOut` if the handshake retries ran out or
+    /// the receiver didn't answer `EOT`.
+    pub fn timeout(&mut self) -> io::Result<Option<Event>> {
+        match self.state {
+            TxState::Handshake if self.attempts < self.config.handshake_retries => {
+                self.attempts += 1;
+                Ok(None)
+            }
+            TxState::Handshake => ioerr!(TimedOut, "no response to handshake"),
+            TxState::Response => {
+                self.state = TxState::Packet(0);
+                Ok(Some(Event::Rejected))
+            }
+            TxState::EotNak | TxState::EotAck => ioerr!(TimedOut, "no response to EOT"),
+            TxState::Idle | TxState::Packet(_) => Ok(None),
+        }
+    }
+
+    /// Handles `byte` received from the receiver. Returns the event it
+    /// completed, if any. A rejected packet is queued to be sent again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the XMODEM protocol indicates an error, with the
+    /// kinds described in
+    /// [`Xmodem::write_packet()`](crate::Xmodem::write_packet).
+    pub fn feed(&mut self, byte: u8) -> io::Result<Option<Event>> {
+        if byte == CAN && self.state != TxState::Idle {
+            self.state = TxState::Idle;
+            return ioerr!(ConnectionAborted, "received CAN");
+        }
+
+        match self.state {
+            TxState::Handshake => match byte {
+                NAK => self.mode = Mode::Checksum,
+                CRC if self.mode == Mode::Crc => {}
+                CRC => return Ok(None),
+                _ => {
+                    self.state = TxState::Idle;
+                    return ioerr!(InvalidData, "first byte not NAK or C");
+                }
+            },
+            TxState::Response => match byte {
+                NAK => {
+                    self.state = TxState::Packet(0);
+                    return Ok(Some(Event::Rejected));
+                }
+                ACK => {
+                    let number = self.packet;
+                    self.packet = self.packet.wrapping_add(1);
+                    self.state = TxState::Idle;
+                    return Ok(Some(Event::Packet { number, len: self.len }));
+                }
+                // A receiver that repeated its handshake before the first
+                // packet arrived; the ACK or NAK is still to come.
+                CRC => return Ok(None),
+                _ => {
+                    self.state = TxState::Idle;
+                    return ioerr!(InvalidData, "invalid byte");
+                }
+            },
+            TxState::EotNak => {
+                if byte != NAK {
+                    self.state = TxState::Idle;
+                    return ioerr!(InvalidData, "missing NAK for EOT");
+                }
+
+                self.out.push(EOT);
+                self.state = TxState::EotAck;
+                return Ok(None);
+            }
+            TxState::EotAck => {
+                self.state = TxState::Idle;
+                if byte != ACK {
+                    return ioerr!(InvalidData, "missing ACK for EOT");
+                }
+
+                self.started = false;
+                return Ok(Some(Event::End));
+            }
+            TxState::Idle | TxState::Packet(_) => return Ok(None),
+        }
+
+        self.started = true;
+        self.state = TxState::Idle;
+        Ok(Some(Event::Started))
+    }
+}
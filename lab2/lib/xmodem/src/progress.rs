@@ -1,3 +1,5 @@
+use core::time::Duration;
+
 /// Enum representing how much progress has been made transmitting/receiving.
 ///
 /// A value of this type is passed in to the progress callback supplied to
@@ -12,11 +14,51 @@ pub enum Progress {
     Started,
     /// Packet `.0` was transmitted/received.
     Packet(u8),
+    /// Running statistics after a packet was transmitted/received.
+    Transferred(TransferStats),
+    /// A packet was rejected and will be retried.
     NAK,
+    /// A duplicate of packet `.0` was received and discarded.
+    Duplicate(u8),
     Unknown,
 }
 
+/// Statistics about a transfer.
+///
+/// Reported through [`Progress::Transferred`] after every packet and returned
+/// as a summary by methods like [`Xmodem::transmit_with_progress()`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Number of data bytes transferred.
+    pub bytes: usize,
+    /// Expected total number of data bytes, if known.
+    pub total: Option<usize>,
+    /// Number of packets transferred.
+    pub packets: usize,
+    /// Number of packets that were rejected and retried.
+    pub retries: usize,
+    /// Time since the transfer started, if a clock was set with
+    /// [`Xmodem::set_clock()`].
+    pub elapsed: Option<Duration>,
+}
+
+impl TransferStats {
+    /// Returns the average throughput in bytes per second, if the elapsed time
+    /// is known and non-zero.
+    pub fn throughput(&self) -> Option<usize> {
+        let micros = self.elapsed?.as_micros();
+        if micros == 0 {
+            return None;
+        }
+
+        Some((self.bytes as u128 * 1_000_000 / micros) as usize)
+    }
+}
+
 /// Type for progress callbacks.
+///
+/// Any `FnMut(Progress)` can be used as a progress callback; this is the type
+/// of plain function callbacks such as [`noop`].
 pub type ProgressFn = fn(Progress);
 
 /// Noop progress callback.
@@ -1,20 +1,29 @@
 use super::*;
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::mpsc::{Receiver, Sender, RecvTimeoutError, channel};
 use std::io::Cursor;
+use std::time::Duration;
+use crate::lossy::{Fault, Lossy};
 
-struct Pipe(Sender<u8>, Receiver<u8>, Vec<u8>);
+/// One end of a byte pipe. Reads block forever unless `.3` sets a timeout.
+struct Pipe(Sender<u8>, Receiver<u8>, Vec<u8>, Option<Duration>);
 
 fn pipe() -> (Pipe, Pipe) {
     let ((tx1, rx1), (tx2, rx2)) = (channel(), channel());
-    (Pipe(tx1, rx2, vec![]), Pipe(tx2, rx1, vec![]))
+    (Pipe(tx1, rx2, vec![], None), Pipe(tx2, rx1, vec![], None))
 }
 
 impl io::Read for Pipe {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         for i in 0..buf.len() {
-            match self.1.recv() {
+            let result = match self.3 {
+                Some(timeout) => self.1.recv_timeout(timeout),
+                None => self.1.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+
+            match result {
                 Ok(byte) => buf[i] = byte,
-                Err(_) => return Ok(i)
+                Err(RecvTimeoutError::Timeout) => return ioerr!(TimedOut, "pipe timed out"),
+                Err(RecvTimeoutError::Disconnected) => return Ok(i)
             }
         }
 
@@ -75,55 +84,78 @@ fn read_byte() {
 }
 
 #[test]
-fn test_expect_byte() {
-    let mut xmodem = Xmodem::new(Cursor::new(vec![1, 1]));
-    assert_eq!(xmodem.expect_byte(1, "1").expect("expected"), 1);
-    let e = xmodem.expect_byte(2, "1, please").expect_err("expect the unexpected");
-    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+fn test_expected_bytes() {
+    // a packet with the expected number and complement is acknowledged,
+    // without cancelling
+    let mut buffer = vec![0, SOH, 1, 254];
+    buffer.extend_from_slice(&[7; 128]);
+    buffer.extend_from_slice(&[get_checksum(&[7; 128]), 0, 0]);
+    let mut packet = [0u8; 128];
+    let n = Xmodem::new(Cursor::new(buffer.as_mut_slice())).read_packet(&mut packet).expect("read packet");
+    assert_eq!(n, 128);
+    assert_eq!(&packet[..], &[7; 128][..]);
+    assert_eq!(&buffer[133..], &[ACK, 0]);
 }
 
 #[test]
-fn test_expect_byte_or_cancel() {
-    let mut buffer = vec![2, 0];
-    let b = Xmodem::new(Cursor::new(buffer.as_mut_slice()))
-        .expect_byte_or_cancel(2, "it's a 2")
-        .expect("got a 2");
-
-    assert_eq!(b, 2);
-}
+fn test_unexpected_byte() {
+    let mut packet = [0; 128];
+    let e = Xmodem::new(Cursor::new(vec![0, SOH, 2, 253]))
+        .read_packet(&mut packet)
+        .expect_err("packet number");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
 
-#[test]
-fn test_expect_can() {
-    let mut xmodem = Xmodem::new(Cursor::new(vec![CAN]));
-    assert_eq!(xmodem.expect_byte(CAN, "hi").expect("CAN"), CAN);
+    let e = Xmodem::new(Cursor::new(vec![0x42, 0]))
+        .write_packet(&packet)
+        .expect_err("not a NAK");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
 }
 
 #[test]
 fn test_unexpected_can() {
+    let mut packet = [0; 128];
+    let e = Xmodem::new(Cursor::new(vec![0, CAN]))
+        .read_packet(&mut packet)
+        .expect_err("CAN for SOH");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+
     let e = Xmodem::new(Cursor::new(vec![CAN]))
-        .expect_byte(SOH, "want SOH")
-        .expect_err("have CAN");
+        .write_packet(&packet)
+        .expect_err("CAN for NAK");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
 
+    let mut buffer = vec![NAK; 134];
+    buffer[133] = CAN;
+    let e = Xmodem::new(Cursor::new(buffer.as_mut_slice()))
+        .write_packet(&packet)
+        .expect_err("CAN for ACK");
     assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
 }
 
 #[test]
 fn test_cancel_on_unexpected() {
-    let mut buffer = vec![CAN, 0];
+    // the receiver cancels on a bad packet number, complement or first byte,
+    // right after reading it
+    let mut buffer = vec![0, SOH, 2, 253, 0];
     let e = Xmodem::new(Cursor::new(buffer.as_mut_slice()))
-        .expect_byte_or_cancel(SOH, "want SOH")
-        .expect_err("have CAN");
-
-    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
-    assert_eq!(buffer[1], CAN);
+        .read_packet(&mut [0; 128])
+        .expect_err("packet number");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(&buffer[..], &[NAK, SOH, 2, CAN, 0]);
 
-    let mut buffer = vec![0, 0];
+    let mut buffer = vec![0, SOH, 1, 1, 0, 0];
     let e = Xmodem::new(Cursor::new(buffer.as_mut_slice()))
-        .expect_byte_or_cancel(SOH, "want SOH")
-        .expect_err("have 0");
+        .read_packet(&mut [0; 128])
+        .expect_err("complement");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(&buffer[..], &[NAK, SOH, 1, 1, CAN, 0]);
 
+    let mut buffer = vec![0, 0xFF, 0, 0];
+    let e = Xmodem::new(Cursor::new(buffer.as_mut_slice()))
+        .read_packet(&mut [0; 128])
+        .expect_err("first byte");
     assert_eq!(e.kind(), io::ErrorKind::InvalidData);
-    assert_eq!(buffer[1], CAN);
+    assert_eq!(&buffer[..], &[NAK, 0xFF, CAN, 0]);
 }
 
 #[test]
@@ -227,3 +259,1271 @@ fn test_eot() {
 
     assert_eq!(&buffer[..], &[NAK, EOT, NAK, EOT, ACK]);
 }
+
+#[test]
+fn test_crc() {
+    assert_eq!(get_crc(b"123456789"), 0x31C3);
+    assert_eq!(get_crc(&[]), 0);
+    assert_eq!(get_crc(&[0; 128]), 0);
+}
+
+#[test]
+fn test_crc_loop() {
+    let mut input = [0u8; 384];
+    (0..384usize).for_each(|i| input[i] = (i * 7) as u8);
+
+    let (mut tx, mut rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        Xmodem::transmit_with_progress(&input[..], &mut rx, Mode::Crc, PacketSize::Standard, progress::noop)
+            .expect("transmit okay");
+        rx.2
+    });
+
+    let rx_thread = std::thread::spawn(move || {
+        let mut output = [0u8; 384];
+        Xmodem::receive_with_progress(&mut tx, &mut output[..], Mode::Crc, progress::noop)
+            .expect("receive okay");
+        (output, tx.2)
+    });
+
+    let rx_buf = tx_thread.join().expect("tx join okay");
+    let (output, tx_buf) = rx_thread.join().expect("rx join okay");
+    assert_eq!(&input[..], &output[..]);
+
+    // check packet 1 carries a big-endian CRC instead of a checksum
+    let crc = get_crc(&input[..128]).to_be_bytes();
+    assert_eq!(&rx_buf[0..3], &[SOH, 1, 255 - 1]);
+    assert_eq!(&rx_buf[3..(3 + 128)], &input[..128]);
+    assert_eq!(&rx_buf[131..133], &crc[..]);
+    assert_eq!(&rx_buf[133..136], &[SOH, 2, 255 - 2]);
+
+    // check EOT
+    assert_eq!(&rx_buf[(3 * 133)..], &[EOT, EOT]);
+
+    // check receiver responses
+    assert_eq!(&tx_buf, &[CRC, ACK, ACK, ACK, NAK, ACK]);
+}
+
+#[test]
+fn test_crc_transmitter_accepts_checksum_receiver() {
+    let input = [0xAAu8; 128];
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut transmitter = Xmodem::new(rx);
+        transmitter.set_mode(Mode::Crc);
+        transmitter.write_packet(&input).expect("write okay");
+        transmitter.write_packet(&[]).expect("write EOT okay");
+        transmitter.mode()
+    });
+
+    let rx_thread = std::thread::spawn(move || {
+        let mut output = [0u8; 128];
+        Xmodem::receive(tx, &mut output[..]).map(|_| output)
+    });
+
+    assert_eq!(tx_thread.join().expect("tx join okay"), Mode::Checksum);
+    let output = rx_thread.join().expect("rx join okay").expect("rx okay");
+    assert_eq!(&input[..], &output[..]);
+}
+
+#[test]
+fn test_crc_fallback_to_checksum() {
+    let input = [0x55u8; 256];
+    let (mut tx, rx) = pipe();
+    tx.3 = Some(Duration::from_millis(20));
+
+    let tx_thread = std::thread::spawn(move || {
+        Xmodem::transmit_with_progress(&input[..], rx, Mode::Checksum, PacketSize::Standard, progress::noop)
+    });
+
+    let rx_thread = std::thread::spawn(move || {
+        let mut output = [0u8; 256];
+        let mut receiver = Xmodem::new(&mut tx);
+        receiver.set_mode(Mode::Crc);
+        let n = receiver.read_packet(&mut output[..128]).expect("read packet 1");
+        assert_eq!(n, 128);
+        assert_eq!(receiver.mode(), Mode::Checksum);
+        receiver.read_packet(&mut output[128..]).expect("read packet 2");
+        assert_eq!(receiver.read_packet(&mut [0u8; 128]).expect("read EOT"), 0);
+        (output, tx.2)
+    });
+
+    assert_eq!(tx_thread.join().expect("tx join okay").expect("tx okay").bytes, 256);
+    let (output, tx_buf) = rx_thread.join().expect("rx join okay");
+    assert_eq!(&input[..], &output[..]);
+    assert_eq!(&tx_buf[..CRC_ATTEMPTS], &[CRC; CRC_ATTEMPTS]);
+    assert_eq!(&tx_buf[CRC_ATTEMPTS..], &[NAK, ACK, ACK, NAK, ACK]);
+}
+
+#[test]
+fn test_crc_mismatch_naks() {
+    let mut packet = vec![SOH, 1, 254];
+    packet.extend_from_slice(&[1u8; 128]);
+    packet.extend_from_slice(&(get_crc(&[1u8; 128]) ^ 1).to_be_bytes());
+    packet.push(0);
+
+    let mut buffer = vec![0];
+    buffer.extend_from_slice(&packet);
+    let mut xmodem = Xmodem::new(Cursor::new(buffer.as_mut_slice()));
+    xmodem.set_mode(Mode::Crc);
+
+    let e = xmodem.read_packet(&mut [0u8; 128]).expect_err("bad CRC");
+    assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+
+    let buffer = xmodem.inner.into_inner();
+    assert_eq!(buffer[0], CRC);
+    assert_eq!(buffer[buffer.len() - 1], NAK);
+}
+
+#[test]
+fn test_1k_loop() {
+    let mut input = [0u8; 2500];
+    (0..2500usize).for_each(|i| input[i] = (i % 251) as u8);
+
+    let (mut tx, mut rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let n = Xmodem::transmit_with_progress(&input[..], &mut rx, Mode::Crc, PacketSize::OneK, progress::noop)
+            .expect("transmit okay");
+        (n, rx.2)
+    });
+
+    let rx_thread = std::thread::spawn(move || {
+        let mut output = [0u8; 2560];
+        let n = Xmodem::receive_with_progress(&mut tx, &mut output[..], Mode::Crc, progress::noop)
+            .expect("receive okay");
+        (n, output)
+    });
+
+    let (written, rx_buf) = tx_thread.join().expect("tx join okay");
+    let (received, output) = rx_thread.join().expect("rx join okay");
+    assert_eq!(written.bytes, 2500);
+    assert_eq!(received.bytes, 2560);
+    assert_eq!(&input[..], &output[..2500]);
+    assert!(output[2500..].iter().all(|b| *b == 0));
+
+    // two 1K packets followed by four 128-byte packets for the 452-byte tail
+    let (one_k, small) = (3 + 1024 + 2, 3 + 128 + 2);
+    assert_eq!(&rx_buf[0..3], &[STX, 1, 255 - 1]);
+    assert_eq!(&rx_buf[3..(3 + 1024)], &input[..1024]);
+    assert_eq!(&rx_buf[1027..1029], &get_crc(&input[..1024]).to_be_bytes()[..]);
+    assert_eq!(&rx_buf[one_k..(one_k + 3)], &[STX, 2, 255 - 2]);
+    for i in 0..4 {
+        let start = 2 * one_k + i * small;
+        let packet = 3 + i as u8;
+        assert_eq!(&rx_buf[start..(start + 3)], &[SOH, packet, 255 - packet]);
+    }
+    assert_eq!(&rx_buf[(2 * one_k + 4 * small)..], &[EOT, EOT]);
+}
+
+#[test]
+fn test_mixed_packet_sizes() {
+    let (small, large) = ([1u8; 128], [2u8; 1024]);
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut transmitter = Xmodem::new(rx);
+        assert_eq!(transmitter.write_packet(&small).expect("small"), 128);
+        assert_eq!(transmitter.write_packet(&large).expect("large"), 1024);
+        assert_eq!(transmitter.write_packet(&small).expect("small"), 128);
+        transmitter.write_packet(&[]).expect("EOT");
+    });
+
+    let mut receiver = Xmodem::new(tx);
+    let mut buf = [0u8; 1024];
+    assert_eq!(receiver.read_packet(&mut buf).expect("small"), 128);
+    assert_eq!(&buf[..128], &small[..]);
+    assert_eq!(receiver.read_packet(&mut buf).expect("large"), 1024);
+    assert_eq!(&buf[..], &large[..]);
+    assert_eq!(receiver.read_packet(&mut buf).expect("small"), 128);
+    assert_eq!(&buf[..128], &small[..]);
+    assert_eq!(receiver.read_packet(&mut buf).expect("EOT"), 0);
+    tx_thread.join().expect("tx join okay");
+}
+
+#[test]
+fn test_1k_packet_short_buffer() {
+    let mut buffer = vec![0, STX, 1, 254, 0];
+    let mut xmodem = Xmodem::new(Cursor::new(buffer.as_mut_slice()));
+    let e = xmodem.read_packet(&mut [0u8; 128]).expect_err("buf too short");
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(buffer[2], CAN);
+}
+
+#[test]
+fn test_ymodem_header_roundtrip() {
+    let header = Header::new("kernel8.img", Some(1234), Some(0o14350255210)).expect("header");
+    let mut packet = [0u8; 1024];
+    assert_eq!(header.encode(&mut packet), 128);
+    let expected = b"kernel8.img\x001234 14350255210\x00";
+    assert_eq!(&packet[..expected.len()], &expected[..]);
+
+    let decoded = Header::decode(&packet[..128]).expect("decode").expect("not empty");
+    assert_eq!(decoded.name(), "kernel8.img");
+    assert_eq!(decoded.size, Some(1234));
+    assert_eq!(decoded.mtime, Some(0o14350255210));
+
+    let long_name = "a".repeat(MAX_NAME_LEN);
+    let header = Header::new(&long_name, Some(1), None).expect("long header");
+    assert_eq!(header.encode(&mut packet), 1024);
+    let decoded = Header::decode(&packet).expect("decode").expect("not empty");
+    assert_eq!(decoded.name(), &long_name[..]);
+    assert_eq!(decoded.size, Some(1));
+    assert_eq!(decoded.mtime, None);
+
+    assert!(Header::decode(&[0u8; 128]).expect("decode").is_none());
+    let e = Header::decode(b"name\0twelve\0").expect_err("bad size");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let e = Header::new("", None, None).expect_err("empty name");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = Header::new(&"a".repeat(MAX_NAME_LEN + 1), None, None).expect_err("long name");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_ymodem_batch() {
+    let first: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    let second: Vec<u8> = (0..2000).map(|i| (i * 3) as u8).collect();
+    let (first_tx, second_tx) = (first.clone(), second.clone());
+
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new(rx);
+        let header = Header::new("first.bin", Some(first_tx.len()), Some(1000)).expect("header");
+        assert_eq!(ymodem.send_file(&header, &first_tx[..], PacketSize::Standard).expect("send"), 300);
+        let header = Header::new("second.bin", Some(second_tx.len()), None).expect("header");
+        assert_eq!(ymodem.send_file(&header, &second_tx[..], PacketSize::OneK).expect("send"), 2000);
+        ymodem.finish().expect("finish");
+    });
+
+    let mut ymodem = Ymodem::new(tx);
+    let mut files = vec![];
+    while let Some(header) = ymodem.next_file().expect("header") {
+        let mut data = vec![];
+        let n = ymodem.receive_file(&header, &mut data).expect("receive");
+        assert_eq!(n, data.len());
+        files.push((header, data));
+    }
+    tx_thread.join().expect("tx join okay");
+
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].0.name(), "first.bin");
+    assert_eq!(files[0].0.mtime, Some(1000));
+    assert_eq!(files[0].1, first);
+    assert_eq!(files[1].0.name(), "second.bin");
+    assert_eq!(files[1].0.mtime, None);
+    assert_eq!(files[1].1, second);
+}
+
+#[test]
+fn test_ymodem_unknown_size() {
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut ymodem = Ymodem::new(rx);
+        let header = Header::new("stdin", None, None).expect("header");
+        ymodem.send_file(&header, &[7u8; 100][..], PacketSize::Standard).expect("send");
+        ymodem.finish().expect("finish");
+    });
+
+    let mut ymodem = Ymodem::new(tx);
+    let header = ymodem.next_file().expect("header").expect("a file");
+    assert_eq!(header.size, None);
+    let mut data = vec![];
+    assert_eq!(ymodem.receive_file(&header, &mut data).expect("receive"), 128);
+    assert_eq!(&data[..100], &[7u8; 100][..]);
+    assert!(ymodem.next_file().expect("end of batch").is_none());
+    tx_thread.join().expect("tx join okay");
+}
+
+#[test]
+fn test_resume_after_failure() {
+    let mut input = [0u8; 640];
+    (0..640usize).for_each(|i| input[i] = (i / 3) as u8);
+
+    // The receiver goes away after acknowledging two packets.
+    let (mut tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut at = Resume::default();
+        Xmodem::transmit_resumable(&input[..], rx, Mode::Crc, PacketSize::Standard, progress::noop, &mut at)
+            .expect_err("receiver went away");
+        at
+    });
+
+    let mut output = [0u8; 640];
+    let receiver_at = {
+        let mut receiver = Xmodem::new(&mut tx);
+        receiver.set_mode(Mode::Crc);
+        receiver.read_packet(&mut output[..128]).expect("packet 1");
+        receiver.read_packet(&mut output[128..256]).expect("packet 2");
+        receiver.position()
+    };
+    drop(tx);
+
+    let sender_at = tx_thread.join().expect("tx join okay");
+    assert_eq!(sender_at, Resume::new(3, 256));
+    assert_eq!(receiver_at, sender_at);
+
+    // Both sides pick up where they left off on a new link.
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut at = sender_at;
+        let n = Xmodem::transmit_resumable(&input[at.offset()..], rx, Mode::Crc, PacketSize::Standard, progress::noop, &mut at)
+            .expect("transmit okay");
+        (n, at)
+    });
+
+    let mut at = receiver_at;
+    let n = Xmodem::receive_resumable(tx, &mut output[at.offset()..], Mode::Crc, progress::noop, &mut at)
+        .expect("receive okay");
+
+    let (written, sender_at) = tx_thread.join().expect("tx join okay");
+    assert_eq!((written.bytes, n.bytes), (384, 384));
+    assert_eq!(sender_at, Resume::new(6, 640));
+    assert_eq!(at, sender_at);
+    assert_eq!(&input[..], &output[..]);
+}
+
+#[test]
+fn test_resume_packet_number() {
+    let mut input = [0u8; 128];
+    input[0] = 9;
+
+    let (mut tx, mut rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut at = Resume::new(300, 299 * 128);
+        Xmodem::transmit_resumable(&input[..], &mut rx, Mode::Checksum, PacketSize::Standard, progress::noop, &mut at)
+            .expect("transmit okay");
+        assert_eq!(at, Resume::new(301, 300 * 128));
+        rx.2
+    });
+
+    let mut output = [0u8; 128];
+    let mut at = Resume::new(300, 299 * 128);
+    Xmodem::receive_resumable(&mut tx, &mut output[..], Mode::Checksum, progress::noop, &mut at)
+        .expect("receive okay");
+    assert_eq!(output[0], 9);
+
+    // block 300 goes out as packet number 300 mod 256
+    let rx_buf = tx_thread.join().expect("tx join okay");
+    assert_eq!(&rx_buf[0..3], &[SOH, 44, 255 - 44]);
+}
+
+#[test]
+fn test_stateful_progress_and_stats() {
+    static TICKS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    fn clock() -> Duration {
+        Duration::from_millis(TICKS.fetch_add(100, std::sync::atomic::Ordering::SeqCst))
+    }
+
+    let input = [3u8; 300];
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        Xmodem::transmit_with_progress(&input[..], rx, Mode::Crc, PacketSize::Standard, progress::noop)
+    });
+
+    let mut packets = vec![];
+    let mut last = None;
+    let mut receiver = Xmodem::new_with_progress(tx, |p| match p {
+        Progress::Packet(n) => packets.push(n),
+        Progress::Transferred(stats) => last = Some(stats),
+        _ => {}
+    });
+    receiver.set_clock(clock);
+    receiver.set_total(Some(300));
+    let mut output = vec![];
+    assert_eq!(receiver.receive_data(&mut output, Some(300)).expect("receive okay"), 300);
+    let stats = receiver.stats();
+    drop(receiver);
+
+    let sent = tx_thread.join().expect("tx join okay").expect("tx okay");
+    assert_eq!(sent.bytes, 300);
+    assert_eq!(sent.packets, 3);
+    assert_eq!(sent.retries, 0);
+    assert_eq!(sent.elapsed, None);
+    assert_eq!(sent.throughput(), None);
+
+    assert_eq!(&output[..], &input[..]);
+    assert_eq!(packets, vec![1, 2, 3]);
+    assert_eq!(last, Some(stats));
+    assert_eq!(stats.bytes, 384);
+    assert_eq!(stats.total, Some(300));
+    assert_eq!(stats.packets, 3);
+    assert_eq!(stats.elapsed, Some(Duration::from_millis(300)));
+    assert_eq!(stats.throughput(), Some(1280));
+}
+
+#[test]
+fn test_stats_count_retries() {
+    let mut packet = vec![SOH, 1, 254];
+    packet.extend_from_slice(&[5u8; 128]);
+    packet.push(0);
+
+    // a corrupted copy of the packet followed by the correct one
+    let mut buffer = vec![0];
+    buffer.extend_from_slice(&packet);
+    buffer.push(0);
+    buffer.extend_from_slice(&packet);
+    let checksum = buffer.len() - 1;
+    buffer[checksum] = get_checksum(&[5u8; 128]);
+    buffer.push(0);
+
+    let mut naks = 0;
+    {
+        let mut xmodem = Xmodem::new_with_progress(Cursor::new(buffer.as_mut_slice()), |p| {
+            if let Progress::NAK = p { naks += 1 }
+        });
+        let mut buf = [0u8; 128];
+        let e = xmodem.read_packet(&mut buf).expect_err("bad checksum");
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(xmodem.read_packet(&mut buf).expect("good packet"), 128);
+        assert_eq!(xmodem.stats().retries, 1);
+        assert_eq!(xmodem.stats().packets, 1);
+    }
+    assert_eq!(naks, 1);
+}
+
+/// A scripted line: reads yield the bytes of `.0` in order, with `None`
+/// timing out once, and end after the last one. Writes are recorded in `.1`.
+struct Script(std::collections::VecDeque<Option<u8>>, Vec<u8>);
+
+fn script(input: &[Option<u8>]) -> Script {
+    Script(input.iter().cloned().collect(), vec![])
+}
+
+/// Returns the script items of a checksum packet numbered `n` with `data`.
+fn scripted_packet(n: u8, data: &[u8; 128]) -> Vec<Option<u8>> {
+    let mut packet = vec![SOH, n, 255 - n];
+    packet.extend_from_slice(data);
+    packet.push(get_checksum(data));
+    packet.into_iter().map(Some).collect()
+}
+
+impl io::Read for Script {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.0.pop_front() {
+            Some(Some(byte)) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            Some(None) => ioerr!(TimedOut, "script timed out"),
+            None => Ok(0),
+        }
+    }
+}
+
+impl io::Write for Script {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config_max_retries() {
+    let config = XmodemConfig { max_retries: 2, ..XmodemConfig::default() };
+    let mut xmodem = Xmodem::new_with_config(script(&[Some(NAK); 4]), config);
+    let e = xmodem.send_data(&[7u8; 128][..], PacketSize::Standard).expect_err("rejected");
+    assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(xmodem.stats().retries, 3);
+
+    // every attempt resends the whole packet
+    let output = xmodem.inner.1;
+    assert_eq!(output.len(), 3 * 132);
+    for packet in output.chunks(132) {
+        assert_eq!(&packet[..3], &[SOH, 1, 254]);
+        assert_eq!(packet[131], get_checksum(&[7u8; 128]));
+    }
+}
+
+#[test]
+fn test_config_default_attempts() {
+    let mut xmodem = Xmodem::new(script(&[Some(NAK); 11]));
+    xmodem.send_data(&[7u8; 128][..], PacketSize::Standard).expect_err("rejected");
+    assert_eq!(xmodem.inner.1.len(), 10 * 132);
+}
+
+#[test]
+fn test_config_static_helpers() {
+    let config = XmodemConfig { max_retries: 1, ..XmodemConfig::default() };
+    let mut rx = script(&[Some(NAK); 3]);
+    let e = Xmodem::transmit_with_config(&[7u8; 128][..], &mut rx, Mode::Checksum,
+                                         PacketSize::Standard, config, progress::noop)
+        .expect_err("rejected");
+    assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(rx.1.len(), 2 * 132);
+
+    let config = XmodemConfig { handshake_retries: 1, ..XmodemConfig::default() };
+    let mut tx = script(&[None, None]);
+    let e = Xmodem::receive_with_config(&mut tx, vec![], Mode::Checksum, config, progress::noop)
+        .expect_err("timed out");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(tx.1, vec![NAK, NAK]);
+}
+
+#[test]
+fn test_config_handshake_retries() {
+    let input = [None, None, Some(NAK), Some(ACK), Some(NAK), Some(ACK)];
+    let config = XmodemConfig { handshake_retries: 2, ..XmodemConfig::default() };
+    let mut xmodem = Xmodem::new_with_config(script(&input), config);
+    assert_eq!(xmodem.send_data(&[1u8; 128][..], PacketSize::Standard).expect("sent"), 128);
+
+    let config = XmodemConfig { handshake_retries: 1, ..XmodemConfig::default() };
+    let mut xmodem = Xmodem::new_with_config(script(&input), config);
+    let e = xmodem.send_data(&[1u8; 128][..], PacketSize::Standard).expect_err("timed out");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+    // a checksum receiver retries its NAK as well
+    let mut xmodem = Xmodem::new_with_config(script(&[None, None]), config);
+    let e = xmodem.read_packet(&mut [0u8; 128]).expect_err("timed out");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(xmodem.inner.1, vec![NAK, NAK]);
+}
+
+#[test]
+fn test_config_byte_timeout() {
+    static TICKS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    fn clock() -> Duration {
+        Duration::from_millis(TICKS.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
+
+    let input = [None, None, None, Some(0x42)];
+    let e = Xmodem::new(script(&input)).read_byte(false).expect_err("no byte timeout");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+    let config = XmodemConfig { byte_timeout: Some(Duration::from_secs(1)), ..XmodemConfig::default() };
+    let mut xmodem = Xmodem::new_with_config(script(&input), config);
+    xmodem.set_clock(clock);
+    assert_eq!(xmodem.read_byte(false).expect("byte"), 0x42);
+
+    let config = XmodemConfig { byte_timeout: Some(Duration::from_millis(2)), ..config };
+    let mut xmodem = Xmodem::new_with_config(script(&[None; 8]), config);
+    xmodem.set_clock(clock);
+    let e = xmodem.read_byte(false).expect_err("byte timeout");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(xmodem.inner.0.len(), 6);
+
+    // the handshake is sent again after each byte timeout, falling back from
+    // CRC to checksum mode, until the retries run out
+    let mut xmodem = Xmodem::new_with_config(script(&[None; 8]), config);
+    xmodem.set_clock(clock);
+    xmodem.set_mode(Mode::Crc);
+    let e = xmodem.read_packet(&mut [0u8; 128]).expect_err("handshake timeout");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(&xmodem.inner.1, &[CRC, CRC, CRC, NAK]);
+}
+
+#[test]
+fn test_config_double_can() {
+    let config = XmodemConfig { double_can: true, ..XmodemConfig::default() };
+    let mut xmodem = Xmodem::new_with_config(script(&[Some(0x42)]), config);
+    let e = xmodem.read_packet(&mut [0u8; 128]).expect_err("bad first byte");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(xmodem.inner.1, vec![NAK, CAN, CAN]);
+}
+
+#[test]
+fn test_config_purge_before_nak() {
+    let mut input = scripted_packet(1, &[9u8; 128]);
+    let last = input.len() - 1;
+    input[last] = Some(0);
+    input.extend_from_slice(&[Some(SOH), Some(1), Some(2), None]);
+    input.extend(scripted_packet(1, &[9u8; 128]));
+
+    let config = XmodemConfig { purge_before_nak: true, ..XmodemConfig::default() };
+    let mut xmodem = Xmodem::new_with_config(script(&input), config);
+    let mut buf = [0u8; 128];
+    let e = xmodem.read_packet(&mut buf).expect_err("bad checksum");
+    assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+    assert_eq!(xmodem.read_packet(&mut buf).expect("good packet"), 128);
+    assert_eq!(&buf[..], &[9u8; 128][..]);
+    assert_eq!(xmodem.inner.1, vec![NAK, NAK, ACK]);
+}
+
+/// A reader that always fails.
+struct Broken;
+
+impl io::Read for Broken {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        ioerr!(Other, "broken source")
+    }
+}
+
+#[test]
+fn test_cancel() {
+    let mut xmodem = Xmodem::new(script(&[Some(1), Some(2), None, Some(3)]));
+    xmodem.cancel().expect("cancel");
+    assert_eq!(xmodem.inner.1, vec![CAN, CAN]);
+    assert_eq!(xmodem.inner.0, vec![Some(3)]);
+}
+
+#[test]
+fn test_cancel_on_source_error() {
+    use std::io::Read;
+
+    let data = (&[1u8; 128][..]).chain(Broken);
+    let mut xmodem = Xmodem::new(script(&[Some(NAK), Some(ACK)]));
+    let e = xmodem.send_data(data, PacketSize::Standard).expect_err("source fails");
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert_eq!(xmodem.stats().packets, 1);
+
+    let output = xmodem.inner.1;
+    assert_eq!(&output[output.len() - 2..], &[CAN, CAN]);
+}
+
+#[test]
+fn test_cancel_on_sink_error() {
+    let mut input = scripted_packet(1, &[4u8; 128]);
+    input.extend(scripted_packet(2, &[4u8; 128]));
+    let mut xmodem = Xmodem::new(script(&input));
+    let e = xmodem.receive_data(&mut [][..], None).expect_err("sink fails");
+    assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+
+    // the rest of the line is drained
+    assert_eq!(xmodem.inner.1, vec![NAK, ACK, CAN, CAN]);
+    assert!(xmodem.inner.0.is_empty());
+}
+
+#[test]
+fn test_transmitter_aborted_by_cancel() {
+    let input = [6u8; 384];
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || Xmodem::transmit(&input[..], rx));
+    let rx_thread = std::thread::spawn(move || {
+        let mut output = [0u8; 128];
+        Xmodem::receive(tx, &mut output[..])
+    });
+
+    let e = tx_thread.join().expect("tx join okay").expect_err("cancelled");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    let e = rx_thread.join().expect("rx join okay").expect_err("sink full");
+    assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(get_crc32(b"123456789"), 0xCBF4_3926);
+}
+
+/// Returns `len` bytes of data covering every byte value.
+fn zmodem_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 256) as u8).collect()
+}
+
+/// Runs a ZMODEM session sending `files` over a pipe whose sender side is
+/// `wrap`ped. The receiver resumes each file at `offset`. Returns the received
+/// headers and contents and the sender's retries.
+fn zmodem_session<W>(files: Vec<(Header, Vec<u8>)>, offset: usize, wrap: W) -> (Vec<(Header, Vec<u8>)>, usize)
+    where W: FnOnce(Pipe) -> Corrupt + Send + 'static
+{
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut zmodem = Zmodem::new(wrap(rx));
+        zmodem.set_window(2048);
+        let mut retries = 0;
+        for (header, data) in files {
+            let n = zmodem.send_file(&header, Cursor::new(&data)).expect("send file");
+            assert_eq!(n, data.len() - offset);
+            retries += zmodem.stats().retries;
+        }
+        zmodem.finish().expect("finish");
+        retries
+    });
+
+    let mut zmodem = Zmodem::new(tx);
+    let mut received = vec![];
+    while let Some(header) = zmodem.next_file().expect("next file") {
+        let mut data = vec![];
+        zmodem.receive_file(&header, &mut data, offset).expect("receive file");
+        received.push((header, data));
+    }
+
+    (received, tx_thread.join().expect("tx join okay"))
+}
+
+/// A writer that flips the lowest bit of the `.1`th byte written to `.0`.
+struct Corrupt(Pipe, Option<usize>);
+
+impl io::Read for Corrupt {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl io::Write for Corrupt {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buf = buf.to_vec();
+        if let Some(at) = self.1 {
+            if at < buf.len() {
+                buf[at] ^= 1;
+                self.1 = None;
+            } else {
+                self.1 = Some(at - buf.len());
+            }
+        }
+
+        self.0.write(&buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[test]
+fn test_zmodem_subpacket_escaping() {
+    let data = (0..=255u8).collect::<Vec<_>>();
+    let mut line = script(&[]);
+    Zmodem::new(&mut line).write_subpacket(&data, b'k').expect("write subpacket");
+    let encoded = line.1;
+    assert!(!encoded[..encoded.len() - 4].iter().any(|b| [0x11, 0x13, 0x91, 0x93].contains(b)));
+
+    let mut receiver = Zmodem::new(script(&encoded.into_iter().map(Some).collect::<Vec<_>>()));
+    let mut buf = [0u8; SUBPACKET_LEN];
+    let (n, _) = receiver.read_subpacket(&mut buf).expect("read subpacket");
+    assert_eq!(&buf[..n], &data[..]);
+}
+
+#[test]
+fn test_zmodem_batch() {
+    let files = vec![
+        (Header::new("kernel8.img", Some(10_000), Some(0o1234)).unwrap(), zmodem_data(10_000)),
+        (Header::new("empty", Some(0), None).unwrap(), vec![]),
+        (Header::new("config.txt", Some(1024), None).unwrap(), zmodem_data(1024)),
+    ];
+
+    let (received, retries) = zmodem_session(files.clone(), 0, |p| Corrupt(p, None));
+    assert_eq!(retries, 0);
+    assert_eq!(received.len(), files.len());
+    for ((header, data), (rx_header, rx_data)) in files.iter().zip(received.iter()) {
+        assert_eq!(header.name(), rx_header.name());
+        assert_eq!(header.size, rx_header.size);
+        assert_eq!(header.mtime, rx_header.mtime);
+        assert_eq!(data, rx_data);
+    }
+}
+
+#[test]
+fn test_zmodem_crash_recovery() {
+    let data = zmodem_data(5000);
+    let files = vec![(Header::new("partial", Some(5000), None).unwrap(), data.clone())];
+    let (received, _) = zmodem_session(files, 3000, |p| Corrupt(p, None));
+    assert_eq!(&received[0].1[..], &data[3000..]);
+}
+
+#[test]
+fn test_zmodem_recovers_from_corruption() {
+    let data = zmodem_data(12_000);
+    let files = vec![(Header::new("noisy", Some(12_000), None).unwrap(), data.clone())];
+    let (received, retries) = zmodem_session(files, 0, |p| Corrupt(p, Some(4000)));
+    assert!(retries >= 1);
+    assert_eq!(received[0].1, data);
+}
+
+#[test]
+fn test_zmodem_cancel() {
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let mut zmodem = Zmodem::new(rx);
+        let header = Header::new("cancelled", Some(100_000), None).unwrap();
+        let data = zmodem_data(100_000);
+        zmodem.send_file(&header, Cursor::new(&data))
+    });
+
+    // The receiver's sink fills up and it cancels the session.
+    let mut zmodem = Zmodem::new(tx);
+    let header = zmodem.next_file().expect("next file").expect("a file");
+    let e = zmodem.receive_file(&header, &mut [0u8; 1000][..], 0).expect_err("sink full");
+    assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+    drop(zmodem);
+
+    let e = tx_thread.join().expect("tx join okay").expect_err("cancelled");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+/// Returns a pipe whose ends time out after `ms` milliseconds.
+fn timed_pipe(ms: u64) -> (Pipe, Pipe) {
+    let (mut a, mut b) = pipe();
+    a.3 = Some(Duration::from_millis(ms));
+    b.3 = Some(Duration::from_millis(ms));
+    (a, b)
+}
+
+#[test]
+fn test_lossy_schedule_is_deterministic() {
+    let run = || {
+        let mut line = Lossy::new(vec![], 7).at(1, Fault::Drop).at(2, Fault::Flip(0x80)).flips(100);
+        io::Write::write_all(&mut line, &[0x55; 200]).expect("write");
+        let injected = line.injected().to_vec();
+        (injected, line.into_inner())
+    };
+
+    let (injected, written) = run();
+    assert_eq!(&injected[..2], &[(1, Fault::Drop), (2, Fault::Flip(0x80))]);
+    assert!(injected.len() > 2);
+    assert_eq!(written[1], 0xD5);
+    assert_eq!(run(), (injected, written));
+}
+
+#[test]
+fn test_lossy_checksum_failures() {
+    let mut input = [0u8; 512];
+    (0..512usize).for_each(|i| input[i] = (i % 13) as u8);
+
+    for &mode in &[Mode::Checksum, Mode::Crc] {
+        // flip a bit in the data of the first and third packets, and twice in
+        // the second packet's data: attempts 0, 2, 3 and 5 are rejected
+        let len = if mode == Mode::Crc { 133 } else { 132 };
+        let (tx, rx) = pipe();
+        let mut line = Lossy::new(rx, 0)
+            .at(3 + 10, Fault::Flip(0x01))
+            .at(2 * len + 3 + 60, Fault::Flip(0x40))
+            .at(3 * len + 3 + 100, Fault::Flip(0x02))
+            .at(5 * len + 3 + 127, Fault::Flip(0x80));
+        let tx_thread = std::thread::spawn(move || {
+            let stats = Xmodem::transmit_with_progress(&input[..], &mut line, mode, PacketSize::Standard, progress::noop);
+            (stats, line.injected().len())
+        });
+
+        let mut output = [0u8; 512];
+        let received = Xmodem::receive_with_progress(tx, &mut output[..], mode, progress::noop).expect("rx okay");
+        let (sent, injected) = tx_thread.join().expect("tx join okay");
+        let sent = sent.expect("tx okay");
+
+        assert_eq!(injected, 4);
+        assert_eq!((sent.retries, received.retries), (4, 4));
+        assert_eq!(sent.packets, 4);
+        assert_eq!(&output[..], &input[..]);
+    }
+}
+
+#[test]
+fn test_lossy_random_faults_never_corrupt() {
+    let mut input = [0u8; 2048];
+    (0..2048usize).for_each(|i| input[i] = (i * 31 % 256) as u8);
+
+    let mut recovered = 0;
+    for seed in 0..16 {
+        let (tx, rx) = timed_pipe(20);
+        let tx_thread = std::thread::spawn(move || {
+            let mut line = Lossy::new(rx, seed).flips(3);
+            if seed % 2 == 1 {
+                line = line.drops(1).duplicates(1);
+            }
+            Xmodem::transmit_with_progress(&input[..], line, Mode::Crc, PacketSize::Standard, progress::noop)
+        });
+
+        let mut output = [0u8; 2048];
+        let line = Lossy::new(tx, !seed).flips(5);
+        let received = Xmodem::receive_with_progress(line, &mut output[..], Mode::Crc, progress::noop);
+        let sent = tx_thread.join().expect("tx join okay");
+
+        // A transfer may fail, but whatever is reported received is intact.
+        if let (Ok(sent), Ok(received)) = (sent, received) {
+            assert_eq!(received.bytes, 2048);
+            assert_eq!(&output[..], &input[..]);
+            if sent.retries > 0 {
+                recovered += 1;
+            }
+        }
+    }
+
+    assert!(recovered > 0);
+}
+
+#[test]
+fn test_lossy_cancel_in_read_packet() {
+    // the second packet's SOH arrives as CAN
+    let (tx, rx) = timed_pipe(100);
+    let tx_thread = std::thread::spawn(move || {
+        let line = Lossy::new(rx, 0).at(132, Fault::Flip(SOH ^ CAN));
+        Xmodem::transmit(&[1u8; 256][..], line)
+    });
+
+    let mut output = [0u8; 256];
+    let e = Xmodem::receive(tx, &mut output[..]).expect_err("cancelled");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    tx_thread.join().expect("tx join okay").expect_err("receiver gone");
+}
+
+#[test]
+fn test_lossy_bad_packet_number_cancels() {
+    // the receiver cancels on a corrupted packet number, and the transmitter
+    // sees the CAN in place of an ACK
+    let (mut tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        let line = Lossy::new(rx, 0).at(1, Fault::Flip(0x04));
+        Xmodem::transmit(&[1u8; 128][..], line)
+    });
+
+    let mut output = [0u8; 128];
+    let e = Xmodem::receive(&mut tx, &mut output[..]).expect_err("bad packet number");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let e = tx_thread.join().expect("tx join okay").expect_err("cancelled");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    assert_eq!(tx.2, vec![NAK, CAN]);
+}
+
+#[test]
+fn test_lossy_cancel_in_write_packet() {
+    // the receiver's ACK of the first packet arrives as CAN
+    let (tx, rx) = timed_pipe(100);
+    let tx_thread = std::thread::spawn(move || Xmodem::transmit(&[1u8; 256][..], rx));
+
+    let mut output = [0u8; 256];
+    let line = Lossy::new(tx, 0).at(1, Fault::Flip(ACK ^ CAN));
+    Xmodem::receive(line, &mut output[..]).expect_err("transmitter gone");
+    let e = tx_thread.join().expect("tx join okay").expect_err("cancelled");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn test_duplicate_packet_is_reacked() {
+    // the transmitter missed the ACK of packet 1 and sends it again
+    let mut input = scripted_packet(1, &[1u8; 128]);
+    input.extend(scripted_packet(1, &[1u8; 128]));
+    input.extend(scripted_packet(2, &[2u8; 128]));
+    input.extend_from_slice(&[Some(EOT), Some(EOT)]);
+
+    let mut output = vec![];
+    let mut xmodem = Xmodem::new(script(&input));
+    assert_eq!(xmodem.receive_data(&mut output, None).expect("received"), 256);
+    assert_eq!(&output[..128], &[1u8; 128][..]);
+    assert_eq!(&output[128..], &[2u8; 128][..]);
+    assert_eq!(xmodem.inner.1, vec![NAK, ACK, ACK, ACK, NAK, ACK]);
+}
+
+#[test]
+fn test_packet_number_wraparound() {
+    let input: Vec<u8> = (0..300 * 128).map(|i| (i / 128) as u8).collect();
+    let data = input.clone();
+    let (tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || Xmodem::transmit(&data[..], rx));
+
+    let mut packets = vec![];
+    let mut output = vec![];
+    let mut receiver = Xmodem::new_with_progress(tx, |p| {
+        if let Progress::Packet(n) = p { packets.push(n) }
+    });
+    assert_eq!(receiver.receive_data(&mut output, None).expect("rx okay"), 300 * 128);
+    assert_eq!(receiver.position(), Resume::new(301, 300 * 128));
+    drop(receiver);
+
+    assert_eq!(tx_thread.join().expect("tx join okay").expect("tx okay"), 300 * 128);
+    assert_eq!(output, input);
+    assert_eq!(&packets[253..258], &[254, 255, 0, 1, 2]);
+    assert_eq!(packets[299], 44);
+}
+
+#[test]
+fn test_lost_ack_is_retransmitted() {
+    let mut input = [0u8; 384];
+    (0..384usize).for_each(|i| input[i] = (i / 128) as u8 + 1);
+
+    // the receiver's ACK of packet 2 is lost: the transmitter times out and
+    // sends packet 2 again
+    let (mut tx, rx) = pipe();
+    tx.3 = Some(Duration::from_millis(50));
+    let tx_thread = std::thread::spawn(move || {
+        Xmodem::transmit_with_progress(&input[..], tx, Mode::Checksum, PacketSize::Standard, progress::noop)
+    });
+
+    let mut duplicates = vec![];
+    let mut output = [0u8; 384];
+    let line = Lossy::new(rx, 0).at(2, Fault::Drop);
+    let received = {
+        let mut receiver = Xmodem::new_with_progress(line, |p| {
+            if let Progress::Duplicate(n) = p { duplicates.push(n) }
+        });
+        receiver.receive_data(&mut output[..], None).map(|n| (n, receiver.stats()))
+    };
+
+    let sent = tx_thread.join().expect("tx join okay").expect("tx okay");
+    let (n, stats) = received.expect("rx okay");
+    assert_eq!((sent.bytes, n), (384, 384));
+    assert_eq!(sent.retries, 1);
+    assert_eq!((stats.packets, stats.retries), (3, 0));
+    assert_eq!(duplicates, vec![2]);
+    assert_eq!(&output[..], &input[..]);
+}
+
+/// Feeds every byte yielded by `next` to `feed`, collecting the events.
+fn shuttle<F, T>(mut next: F, mut feed: T, events: &mut Vec<Event>)
+    where F: FnMut() -> Option<u8>, T: FnMut(u8) -> io::Result<Option<Event>>
+{
+    while let Some(byte) = next() {
+        events.extend(feed(byte).expect("feed okay"));
+    }
+}
+
+#[test]
+fn test_machines_in_memory() {
+    let mut input = [0u8; 1024 + 256];
+    (0..input.len()).for_each(|i| input[i] = (i % 251) as u8);
+
+    let mut rx = crate::Receiver::new(Mode::Crc);
+    let mut tx = Transmitter::new(Mode::Crc);
+    let (mut rx_events, mut tx_events) = (vec![], vec![]);
+    let mut output = vec![];
+
+    rx.start();
+    tx.start();
+    shuttle(|| rx.next_output(), |b| tx.feed(b), &mut tx_events);
+    assert_eq!(tx_events, vec![Event::Started]);
+    assert_eq!(tx.mode(), Mode::Crc);
+
+    let mut chunks = vec![&input[..1024], &input[1024..1152], &input[1152..]];
+    chunks.reverse();
+    let mut corrupt = true;
+    while let Some(chunk) = chunks.pop() {
+        tx.send(chunk).expect("send okay");
+        loop {
+            tx_events.clear();
+            let second = rx.packet() == 2;
+            let mut offset = 0;
+            shuttle(|| {
+                // flip a data byte of the second packet, once
+                let byte = tx.next_output()?;
+                offset += 1;
+                if corrupt && second && offset == 10 {
+                    corrupt = false;
+                    return Some(!byte);
+                }
+                Some(byte)
+            }, |b| rx.feed(b), &mut rx_events);
+            shuttle(|| rx.next_output(), |b| tx.feed(b), &mut tx_events);
+            match tx_events.as_slice() {
+                [Event::Packet { .. }] => break,
+                [Event::Rejected] => continue,
+                other => panic!("unexpected events {:?}", other),
+            }
+        }
+        output.extend_from_slice(rx.data());
+    }
+
+    tx.finish().expect("finish okay");
+    tx_events.clear();
+    while tx_events.is_empty() {
+        shuttle(|| tx.next_output(), |b| rx.feed(b), &mut rx_events);
+        shuttle(|| rx.next_output(), |b| tx.feed(b), &mut tx_events);
+    }
+
+    assert_eq!(tx_events, vec![Event::End]);
+    assert_eq!(rx_events, vec![
+        Event::Packet { number: 1, len: 1024 },
+        Event::Rejected,
+        Event::Packet { number: 2, len: 128 },
+        Event::Packet { number: 3, len: 128 },
+        Event::End,
+    ]);
+    assert_eq!(&output[..], &input[..]);
+    assert!(!rx.started() && !tx.started());
+}
+
+#[test]
+fn test_receiver_machine_timeouts() {
+    let mut rx = crate::Receiver::new(Mode::Crc);
+    rx.start();
+    for _ in 0..3 {
+        assert_eq!(rx.next_output(), Some(CRC));
+        assert_eq!(rx.timeout().expect("retry"), None);
+    }
+    assert_eq!(rx.mode(), Mode::Checksum);
+    assert_eq!(rx.next_output(), Some(NAK));
+    rx.timeout().expect_err("retries exhausted");
+
+    // a timeout within a packet discards it and asks for it again
+    rx.start();
+    assert_eq!(rx.next_output(), Some(NAK));
+    for &byte in &[SOH, 1, 254, 0, 0] {
+        assert_eq!(rx.feed(byte).expect("feed okay"), None);
+    }
+    assert!(!rx.handshaking());
+    assert_eq!(rx.timeout().expect("rejected"), Some(Event::Rejected));
+    assert_eq!(rx.next_output(), Some(NAK));
+    assert_eq!(rx.next_output(), None);
+    assert_eq!(rx.packet(), 1);
+}
+
+#[test]
+fn test_transmitter_machine_requires_handshake() {
+    let mut tx = Transmitter::new(Mode::Crc);
+    let e = tx.send(&[0; 128]).expect_err("not started");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    tx.finish().expect_err("not started");
+
+    tx.start();
+    assert_eq!(tx.next_output(), None);
+    let e = tx.feed(CAN).expect_err("cancelled");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    assert!(!tx.started());
+}
+
+#[test]
+fn test_transmitter_ignores_repeated_handshake() {
+    let mut tx = Transmitter::new(Mode::Crc);
+    tx.start();
+    assert_eq!(tx.feed(CRC).expect("handshake"), Some(Event::Started));
+    tx.send(&[0x42; 128]).expect("send okay");
+    while tx.next_output().is_some() {}
+
+    // the receiver timed out once before the packet reached it
+    assert_eq!(tx.feed(CRC).expect("second C ignored"), None);
+    assert_eq!(tx.feed(ACK).expect("ACK"), Some(Event::Packet { number: 1, len: 128 }));
+
+    // end to end, with the receiver's first C still queued on the line
+    let input = [0x5Au8; 256];
+    let (mut tx, rx) = pipe();
+    let tx_thread = std::thread::spawn(move || {
+        Xmodem::transmit_with_progress(&input[..], rx, Mode::Crc, PacketSize::Standard, progress::noop)
+    });
+
+    let rx_thread = std::thread::spawn(move || {
+        let mut output = [0u8; 256];
+        io::Write::write_all(&mut tx, &[CRC]).expect("write C");
+        Xmodem::receive_with_progress(tx, &mut output[..], Mode::Crc, progress::noop).map(|_| output)
+    });
+
+    assert_eq!(tx_thread.join().expect("tx join okay").expect("tx okay").bytes, 256);
+    let output = rx_thread.join().expect("rx join okay").expect("rx okay");
+    assert_eq!(&input[..], &output[..]);
+}
+
+#[test]
+fn test_image_header_round_trip() {
+    let data = zmodem_data(300);
+    let header = ImageHeader::new(0x80000, 0x80040, &data, 0);
+    assert_eq!((header.len, header.crc), (300, get_crc32(&data)));
+
+    let encoded = header.encode();
+    assert_eq!(&encoded[..6], b"BIMG\x01\x00");
+    assert_eq!(ImageHeader::decode(&encoded), Ok(header));
+
+    // XMODEM pads the image; the padding is ignored
+    let mut padded = data.clone();
+    padded.resize(384, 0);
+    assert_eq!(header.verify(&padded), Ok(()));
+}
+
+#[test]
+fn test_image_header_errors() {
+    let data = zmodem_data(300);
+    let header = ImageHeader::new(0x80000, 0x80000, &data, 0);
+    let encoded = header.encode();
+
+    assert_eq!(ImageHeader::decode(&[]), Err(ImageError::Truncated { expected: 32, received: 0 }));
+    assert_eq!(ImageHeader::decode(&[0; 32]), Err(ImageError::BadMagic([0; 4])));
+
+    let mut bad = encoded;
+    bad[4] = 2;
+    assert_eq!(ImageHeader::decode(&bad), Err(ImageError::UnsupportedVersion(2)));
+    let mut bad = encoded;
+    bad[7] = 0x80;
+    assert_eq!(ImageHeader::decode(&bad), Err(ImageError::UnsupportedFlags(0x8000)));
+
+    assert_eq!(header.verify(&data[..299]), Err(ImageError::Truncated { expected: 300, received: 299 }));
+    let mut corrupt = data.clone();
+    corrupt[10] ^= 1;
+    match header.verify(&corrupt) {
+        Err(ImageError::BadCrc { expected, .. }) => assert_eq!(expected, header.crc),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+/// Frames `data` like `ttywrite` does, compressed if `lz4` is set, and unpacks
+/// it in a buffer with `room` bytes of scratch space after the image.
+fn unpack_framed(data: &[u8], lz4: bool, room: usize) -> Result<Vec<u8>, ImageError> {
+    let (payload, flags) = if lz4 {
+        let mut compressed = vec![0; max_compressed_len(data.len())];
+        let len = compress(data, &mut compressed).expect("compressed");
+        compressed.truncate(len);
+        (compressed, IMAGE_FLAG_LZ4)
+    } else {
+        (data.to_vec(), 0)
+    };
+
+    let header = ImageHeader::new(0x80000, 0x80000, &payload, flags);
+    let mut buf = header.encode().to_vec();
+    buf.extend_from_slice(&payload);
+    let len = std::cmp::max(buf.len(), data.len() + room);
+    buf.resize(len, 0xaa);
+
+    let decoded = ImageHeader::decode(&buf)?;
+    decoded.verify(&buf[IMAGE_HEADER_LEN..])?;
+    let unpacked = decoded.unpack(&mut buf)?;
+    buf.truncate(unpacked);
+    Ok(buf)
+}
+
+#[test]
+fn test_image_unpack_round_trip() {
+    let mut kernel = vec![];
+    for i in 0..20000u32 {
+        kernel.extend_from_slice(&(i / 7).to_le_bytes());
+    }
+
+    let inputs: Vec<Vec<u8>> = vec![
+        vec![],
+        b"hello".to_vec(),
+        vec![0; 100_000],
+        zmodem_data(3000),
+        (0..70_000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect(),
+        kernel,
+    ];
+
+    for data in &inputs {
+        assert_eq!(&unpack_framed(data, false, 0).unwrap(), data);
+        assert_eq!(&unpack_framed(data, true, 64).unwrap(), data);
+    }
+
+    // Repetitive data shrinks
+    let mut compressed = vec![0; max_compressed_len(100_000)];
+    assert!(compress(&inputs[2], &mut compressed).unwrap() < 1000);
+    assert_eq!(compress(&inputs[2], &mut compressed[..100]), None);
+}
+
+#[test]
+fn test_image_unpack_errors() {
+    let data = zmodem_data(3000);
+    let header = ImageHeader::new(0x80000, 0x80000, &data, 0);
+    assert_eq!(header.unpack(&mut [0; 100]), Err(ImageError::Truncated { expected: 3000, received: 68 }));
+
+    // Without room to grow, the image overtakes its compressed data
+    assert_eq!(unpack_framed(&data, true, 0), Err(ImageError::NoRoom));
+
+    // An image larger than the buffer, claimed by a corrupt length
+    let mut compressed = vec![0; max_compressed_len(data.len())];
+    let len = compress(&data, &mut compressed).unwrap();
+    compressed.truncate(len);
+    compressed[..4].copy_from_slice(&1_000_000u32.to_le_bytes());
+    let header = ImageHeader::new(0x80000, 0x80000, &compressed, IMAGE_FLAG_LZ4);
+    let mut buf = header.encode().to_vec();
+    buf.extend_from_slice(&compressed);
+    buf.resize(4096, 0);
+    assert_eq!(header.unpack(&mut buf), Err(ImageError::TooLarge { len: 1_000_000, max: 4096 }));
+
+    // Truncated or corrupt compressed data
+    let mut compressed = vec![0; max_compressed_len(data.len())];
+    let len = compress(&data, &mut compressed).unwrap();
+    for &(cut, flip) in &[(len - 1, None), (len, Some(6)), (len, Some(len - 8)), (5, None)] {
+        let mut bad = compressed[..cut].to_vec();
+        if let Some(at) = flip {
+            bad[at] ^= 0xff;
+        }
+        let header = ImageHeader::new(0x80000, 0x80000, &bad, IMAGE_FLAG_LZ4);
+        let mut buf = header.encode().to_vec();
+        buf.extend_from_slice(&bad);
+        buf.resize(8192, 0);
+        match header.unpack(&mut buf) {
+            Ok(n) => assert!(n != data.len() || buf[..n] != data[..], "corruption undetected"),
+            Err(ImageError::BadCompression) => {}
+            Err(e) => panic!("unexpected {:?}", e),
+        }
+    }
+}
+
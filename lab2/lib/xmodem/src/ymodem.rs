@@ -0,0 +1,290 @@
+use core::fmt;
+use core::str;
+use core::time::Duration;
+
+use shim::io;
+use shim::ioerr;
+
+use crate::{progress, Mode, PacketSize, Progress, ProgressFn, Resume, TransferStats, Xmodem, XmodemConfig};
+
+/// Maximum length in bytes of a file name carried in a YMODEM header.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// File metadata carried in the header packet (block 0) of a YMODEM transfer.
+///
+/// On the wire, a header is the file name followed by a `NUL`, the file size
+/// in decimal and, separated by a space, the modification time in octal. The
+/// rest of the packet is padded with `NUL`s.
+#[derive(Copy, Clone)]
+pub struct Header {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    /// The exact length of the file in bytes, if known.
+    pub size: Option<usize>,
+    /// The modification time of the file in seconds since the Unix epoch, if
+    /// known.
+    pub mtime: Option<u64>,
+}
+
+impl Header {
+    /// Returns a new header for a file named `name` with the given `size` and
+    /// modification time `mtime`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `name` is empty, longer than
+    /// [`MAX_NAME_LEN`] bytes, or contains a `NUL` byte.
+    pub fn new(name: &str, size: Option<usize>, mtime: Option<u64>) -> io::Result<Header> {
+        let bytes = name.as_bytes();
+        if bytes.is_empty() || bytes.len() > MAX_NAME_LEN || bytes.contains(&0) {
+            return ioerr!(InvalidInput, "invalid file name");
+        }
+
+        let mut header = Header { name: [0; MAX_NAME_LEN], name_len: bytes.len(), size, mtime };
+        header.name[..bytes.len()].copy_from_slice(bytes);
+        Ok(header)
+    }
+
+    /// Returns the file name.
+    pub fn name(&self) -> &str {
+        // `new` and `decode` only ever store valid UTF-8.
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    /// Encodes `self` into `buf`, zeroing the remainder. Returns the length of
+    /// the packet needed to carry the header: 128 or 1024.
+    pub(crate) fn encode(&self, buf: &mut [u8; 1024]) -> usize {
+        buf.iter_mut().for_each(|b| *b = 0);
+        buf[..self.name_len].copy_from_slice(&self.name[..self.name_len]);
+
+        let mut pos = self.name_len + 1;
+        if let Some(size) = self.size {
+            pos = write_number(buf, pos, size as u64, 10);
+            if let Some(mtime) = self.mtime {
+                buf[pos] = b' ';
+                pos = write_number(buf, pos + 1, mtime, 8);
+            }
+        }
+
+        if pos < 128 { 128 } else { 1024 }
+    }
+
+    /// Decodes a header from the header packet `buf`. Returns `None` for the
+    /// empty header that ends a batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the header is malformed.
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<Option<Header>> {
+        if !matches!(buf.first(), Some(b) if *b != 0) {
+            return Ok(None);
+        }
+
+        let name_len = match buf.iter().position(|b| *b == 0) {
+            Some(len) if len <= MAX_NAME_LEN => len,
+            _ => return ioerr!(InvalidData, "invalid header file name"),
+        };
+        let name = match str::from_utf8(&buf[..name_len]) {
+            Ok(name) => name,
+            Err(_) => return ioerr!(InvalidData, "invalid header file name"),
+        };
+
+        let rest = &buf[name_len + 1..];
+        let rest = &rest[..rest.iter().position(|b| *b == 0).unwrap_or(rest.len())];
+        let rest = match str::from_utf8(rest) {
+            Ok(rest) => rest,
+            Err(_) => return ioerr!(InvalidData, "invalid header fields"),
+        };
+
+        let mut fields = rest.split(' ').filter(|f| !f.is_empty());
+        let size = match fields.next().map(|f| f.parse::<usize>()) {
+            Some(Ok(size)) => Some(size),
+            Some(Err(_)) => return ioerr!(InvalidData, "invalid header file size"),
+            None => None,
+        };
+        let mtime = match fields.next().map(|f| u64::from_str_radix(f, 8)) {
+            Some(Ok(mtime)) => Some(mtime),
+            Some(Err(_)) => return ioerr!(InvalidData, "invalid header mtime"),
+            None => None,
+        };
+
+        Header::new(name, size, mtime).map(Some)
+    }
+}
+
+impl fmt::Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Header")
+            .field("name", &self.name())
+            .field("size", &self.size)
+            .field("mtime", &self.mtime)
+            .finish()
+    }
+}
+
+/// Writes the digits of `n` in base `radix` into `buf` starting at `pos`.
+/// Returns the position after the last digit.
+fn write_number(buf: &mut [u8], pos: usize, mut n: u64, radix: u64) -> usize {
+    let mut digits = [0u8; 22];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % radix) as u8;
+        len += 1;
+        n /= radix;
+        if n == 0 {
+            break;
+        }
+    }
+
+    for (i, digit) in digits[..len].iter().rev().enumerate() {
+        buf[pos + i] = *digit;
+    }
+
+    pos + len
+}
+
+/// Implementation of the YMODEM batch protocol on top of [`Xmodem`].
+///
+/// Each file is preceded by a header packet numbered 0 carrying its name, size
+/// and modification time, which lets the receiver truncate the zero padding of
+/// the last packet. A batch is ended by a header packet with an empty name.
+/// YMODEM always starts out in CRC mode.
+pub struct Ymodem<T, P = ProgressFn> {
+    xmodem: Xmodem<T, P>,
+}
+
+impl<T: io::Read + io::Write> Ymodem<T> {
+    /// Returns a new `Ymodem` instance with the internal reader/writer set to
+    /// `inner`. The returned instance can be used for both receiving
+    /// (downloading) and sending (uploading) a batch of files.
+    pub fn new(inner: T) -> Self {
+        Ymodem::new_with_progress(inner, progress::noop)
+    }
+}
+
+impl<T: io::Read + io::Write, P: FnMut(Progress)> Ymodem<T, P> {
+    /// Returns a new `Ymodem` instance with the internal reader/writer set to
+    /// `inner`. The function `f` is used as a callback to indicate progress
+    /// throughout the transfer. See the [`Progress`] enum for more information.
+    pub fn new_with_progress(inner: T, f: P) -> Self {
+        let mut xmodem = Xmodem::new_with_progress(inner, f);
+        xmodem.set_mode(Mode::Crc);
+        Ymodem { xmodem }
+    }
+
+    /// Sets the function used to read the current time to `clock`. See
+    /// [`Xmodem::set_clock()`].
+    pub fn set_clock(&mut self, clock: fn() -> Duration) {
+        self.xmodem.set_clock(clock);
+    }
+
+    /// Sets the retry, timeout and cancellation policy to `config`. See
+    /// [`XmodemConfig`].
+    pub fn set_config(&mut self, config: XmodemConfig) {
+        self.xmodem.set_config(config);
+    }
+
+    /// Returns the statistics of the current or last file's transfer. The
+    /// expected total is the size from the file's header.
+    pub fn stats(&self) -> TransferStats {
+        self.xmodem.stats()
+    }
+
+    /// Sends (uploads) the file described by `header` with contents `data` in
+    /// packets of `size`. Returns the number of bytes sent, excluding padding
+    /// zeroes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the header or any data packet could not be sent.
+    /// See [`Xmodem::write_packet()`].
+    pub fn send_file<R: io::Read>(&mut self, header: &Header, data: R, size: PacketSize) -> io::Result<usize> {
+        self.write_header(Some(header))?;
+        self.xmodem.reset_stats();
+        self.xmodem.set_total(header.size);
+        self.xmodem.send_data(data, size)
+    }
+
+    /// Ends the batch by sending an empty header.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the header could not be sent. See
+    /// [`Xmodem::write_packet()`].
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_header(None)
+    }
+
+    /// Cancels the batch. See [`Xmodem::cancel()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to or reading from the inner stream fails.
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.xmodem.cancel()
+    }
+
+    /// Receives (downloads) the next header of the batch. Returns `None` if the
+    /// sender ended the batch. Otherwise, the file's contents must be read
+    /// with [`Ymodem::receive_file()`] before the next header.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the header could not be read (see
+    /// [`Xmodem::read_packet()`]). An error of kind `InvalidData` is returned
+    /// if the sender ends a transmission instead of sending a header or if the
+    /// header is malformed.
+    pub fn next_file(&mut self) -> io::Result<Option<Header>> {
+        let mut packet = [0u8; 1024];
+        self.xmodem.set_packet(0);
+        for _ in 0..=self.xmodem.config().max_retries {
+            match self.xmodem.read_packet(&mut packet) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+                Ok(0) => return ioerr!(InvalidData, "expected header, got EOT"),
+                Ok(n) => {
+                    // The receiver asks for the file's data with a new 'C'.
+                    self.xmodem.resume(Resume::default());
+                    return Header::decode(&packet[..n]);
+                }
+            }
+        }
+
+        ioerr!(BrokenPipe, "bad receive")
+    }
+
+    /// Receives (downloads) the contents of the file described by `header`
+    /// into `into`. If `header.size` is set, the padding after the last byte
+    /// of the file is discarded. Returns the number of bytes written to `into`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a packet or writing to `into` fails. See
+    /// [`Xmodem::read_packet()`].
+    pub fn receive_file<W: io::Write>(&mut self, header: &Header, into: W) -> io::Result<usize> {
+        self.xmodem.reset_stats();
+        self.xmodem.set_total(header.size);
+        self.xmodem.receive_data(into, header.size)
+    }
+
+    /// Sends a header packet for `header`, or the empty header ending the
+    /// batch if `header` is `None`.
+    fn write_header(&mut self, header: Option<&Header>) -> io::Result<()> {
+        let mut packet = [0u8; 1024];
+        let len = header.map_or(128, |h| h.encode(&mut packet));
+        self.xmodem.set_packet(0);
+        for _ in 0..=self.xmodem.config().max_retries {
+            match self.xmodem.write_packet(&packet[..len]) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+                Ok(_) => {
+                    // The receiver asks for the file's data with a new 'C'.
+                    self.xmodem.resume(Resume::default());
+                    return Ok(());
+                }
+            }
+        }
+
+        ioerr!(BrokenPipe, "bad transmit")
+    }
+}
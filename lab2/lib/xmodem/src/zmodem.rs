@@ -0,0 +1,851 @@
+use core::time::Duration;
+
+use shim::io;
+use shim::ioerr;
+
+use crate::crc::{get_crc32, update_crc, update_crc32};
+use crate::read_ext::ReadExt;
+use crate::{drain, is_timeout, progress, Header, Progress, ProgressFn, TransferStats};
+
+const ZPAD: u8 = b'*';
+const ZDLE: u8 = 0x18;
+const ZBIN: u8 = b'A';
+const ZHEX: u8 = b'B';
+const ZBIN32: u8 = b'C';
+const ZRUB0: u8 = b'l';
+const ZRUB1: u8 = b'm';
+
+const ZRQINIT: u8 = 0;
+const ZRINIT: u8 = 1;
+const ZSINIT: u8 = 2;
+const ZACK: u8 = 3;
+const ZFILE: u8 = 4;
+const ZSKIP: u8 = 5;
+const ZNAK: u8 = 6;
+const ZABORT: u8 = 7;
+const ZFIN: u8 = 8;
+const ZRPOS: u8 = 9;
+const ZDATA: u8 = 10;
+const ZEOF: u8 = 11;
+const ZFERR: u8 = 12;
+const ZCAN: u8 = 16;
+
+/// Ends a frame; no response expected.
+const ZCRCE: u8 = b'h';
+/// Continues a frame; no response expected.
+const ZCRCG: u8 = b'i';
+/// Continues a frame; `ZACK` expected.
+const ZCRCQ: u8 = b'j';
+/// Ends a frame; `ZACK` expected.
+const ZCRCW: u8 = b'k';
+
+/// `ZRINIT` flags: full duplex, overlapped I/O and CRC-32 frames.
+const CANFDX: u8 = 0x01;
+const CANOVIO: u8 = 0x02;
+const CANFC32: u8 = 0x20;
+
+/// `ZFILE` conversion option: binary transfer.
+const ZCBIN: u8 = 1;
+
+const CAN: u8 = ZDLE;
+const BS: u8 = 0x08;
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Number of times a header or data is resent before a transfer fails.
+const MAX_RETRIES: usize = 10;
+
+/// Number of consecutive `CAN` bytes that abort a session.
+const ABORT_CANS: usize = 5;
+
+/// Maximum number of data bytes in a ZMODEM data subpacket.
+pub const SUBPACKET_LEN: usize = 1024;
+
+/// Default number of bytes sent between acknowledgement requests.
+const WINDOW: usize = 8 * SUBPACKET_LEN;
+
+/// A ZMODEM header: a frame type and four bytes of flags or a little-endian
+/// file position.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Frame {
+    kind: u8,
+    data: [u8; 4],
+}
+
+impl Frame {
+    fn new(kind: u8, data: [u8; 4]) -> Frame {
+        Frame { kind, data }
+    }
+
+    /// Returns a header of type `kind` carrying the file position `pos`.
+    fn at(kind: u8, pos: usize) -> Frame {
+        Frame::new(kind, (pos as u32).to_le_bytes())
+    }
+
+    /// Returns the file position carried by the header.
+    fn position(&self) -> usize {
+        u32::from_le_bytes(self.data) as usize
+    }
+
+    /// Returns the header's `ZF0` flags byte.
+    fn flags(&self) -> u8 {
+        self.data[3]
+    }
+
+    /// Returns the type and data bytes covered by the header's CRC.
+    fn bytes(&self) -> [u8; 5] {
+        let d = self.data;
+        [self.kind, d[0], d[1], d[2], d[3]]
+    }
+}
+
+/// A byte read from a ZDLE-escaped stream.
+enum Escaped {
+    Byte(u8),
+    /// The end of a data subpacket with the given terminator.
+    End(u8),
+}
+
+/// Writes `byte` into `out` at `len`, escaping it with `ZDLE` if it could be
+/// mistaken for a control character. Returns the new length.
+fn escape(byte: u8, out: &mut [u8], len: usize) -> usize {
+    match byte {
+        ZDLE | 0x10 | XON | XOFF | 0x98 | 0x90 | 0x91 | 0x93 => {
+            out[len] = ZDLE;
+            out[len + 1] = byte ^ 0x40;
+            len + 2
+        }
+        _ => {
+            out[len] = byte;
+            len + 1
+        }
+    }
+}
+
+/// Writes `byte` as two lowercase hex digits into `out` at `len`. Returns the
+/// new length.
+fn hex(byte: u8, out: &mut [u8], len: usize) -> usize {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    out[len] = DIGITS[(byte >> 4) as usize];
+    out[len + 1] = DIGITS[(byte & 0xf) as usize];
+    len + 2
+}
+
+/// Implementation of the ZMODEM protocol.
+///
+/// Unlike XMODEM, the sender streams data without waiting for each packet to
+/// be acknowledged. Data is sent in subpackets of up to [`SUBPACKET_LEN`]
+/// bytes, protected by a CRC-32 if the receiver supports it. After every
+/// window of data the sender asks for an acknowledgement, and at most two
+/// windows are ever unacknowledged. When a subpacket is damaged, the receiver
+/// asks the sender to rewind to the last good position, which is also how a
+/// receiver resumes a partially received file after a crash.
+///
+/// File metadata is carried in a [`Header`], as in YMODEM.
+pub struct Zmodem<T, P = ProgressFn> {
+    inner: T,
+    progress: P,
+    started: bool,
+    crc32: bool,
+    frame_crc32: bool,
+    window: usize,
+    stats: TransferStats,
+    clock: Option<fn() -> Duration>,
+    start: Option<Duration>,
+}
+
+impl<T: io::Read + io::Write> Zmodem<T> {
+    /// Returns a new `Zmodem` instance with the internal reader/writer set to
+    /// `inner`. The returned instance can be used for both receiving
+    /// (downloading) and sending (uploading) a batch of files.
+    pub fn new(inner: T) -> Self {
+        Zmodem::new_with_progress(inner, progress::noop)
+    }
+}
+
+impl<T: io::Read + io::Write, P: FnMut(Progress)> Zmodem<T, P> {
+    /// Returns a new `Zmodem` instance with the internal reader/writer set to
+    /// `inner`. The function `f` is used as a callback to indicate progress
+    /// throughout the transfer. See the [`Progress`] enum for more information.
+    pub fn new_with_progress(inner: T, f: P) -> Self {
+        Zmodem {
+            inner,
+            progress: f,
+            started: false,
+            crc32: false,
+            frame_crc32: false,
+            window: WINDOW,
+            stats: TransferStats::default(),
+            clock: None,
+            start: None,
+        }
+    }
+
+    /// Sets the number of bytes the sender streams before asking for an
+    /// acknowledgement to `window`. Larger windows are faster on clean lines;
+    /// smaller ones resend less data after an error.
+    pub fn set_window(&mut self, window: usize) {
+        self.window = core::cmp::max(window, 1);
+    }
+
+    /// Sets the function used to read the current time to `clock`. See
+    /// [`Xmodem::set_clock()`](crate::Xmodem::set_clock()).
+    pub fn set_clock(&mut self, clock: fn() -> Duration) {
+        self.clock = Some(clock);
+    }
+
+    /// Returns the statistics of the current or last file's transfer. Bytes
+    /// skipped by resuming a partial file are not counted.
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Clears the statistics for a new file of `total` bytes.
+    fn reset_stats(&mut self, total: Option<usize>) {
+        self.stats = TransferStats { total, ..TransferStats::default() };
+        self.start = self.clock.map(|clock| clock());
+    }
+
+    /// Records that a data subpacket was transferred, bringing the total to
+    /// `bytes` bytes, and reports it to the progress callback.
+    fn record_progress(&mut self, bytes: usize) {
+        self.stats.packets += 1;
+        self.stats.bytes = bytes;
+        if let (Some(clock), Some(start)) = (self.clock, self.start) {
+            self.stats.elapsed = clock().checked_sub(start);
+        }
+        (self.progress)(Progress::Transferred(self.stats));
+    }
+
+    /// Records that the transfer rewound after an error and reports it to the
+    /// progress callback.
+    fn record_retry(&mut self) {
+        self.stats.retries += 1;
+        (self.progress)(Progress::NAK);
+    }
+
+    /// Sends (uploads) the file described by `header` with contents `data`,
+    /// starting a session with the receiver first if necessary. Returns the
+    /// number of bytes of `data` sent, which is less than its length if the
+    /// receiver resumed a partially received file.
+    ///
+    /// `data` is seeked to the position the receiver asks for when it resumes
+    /// a file or recovers from an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or writing to the inner stream fails or the
+    /// receiver doesn't respond. An error of kind `ConnectionAborted` is
+    /// returned if the receiver aborts the session. If reading or seeking
+    /// `data` fails, the session is cancelled (see [`Zmodem::cancel()`]) and
+    /// the error is returned.
+    pub fn send_file<R>(&mut self, header: &Header, mut data: R) -> io::Result<usize>
+        where R: io::Read + io::Seek
+    {
+        if !self.started {
+            self.start_send()?;
+        }
+
+        let start = match self.send_file_header(header)? {
+            Some(start) => start,
+            None => return Ok(0),
+        };
+        self.reset_stats(header.size.map(|size| size.saturating_sub(start)));
+
+        let mut buf = [0u8; SUBPACKET_LEN];
+        let mut pos = start;
+        let mut acked = start;
+        let mut failures = 0;
+        'restart: loop {
+            if let Err(e) = data.seek(io::SeekFrom::Start(pos as u64)) {
+                let _ = self.cancel();
+                return Err(e);
+            }
+
+            self.write_header(Frame::at(ZDATA, pos))?;
+            let mut outstanding = 0;
+            let mut unacked = 0;
+            loop {
+                let n = match data.read_max(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        let _ = self.cancel();
+                        return Err(e);
+                    }
+                };
+
+                if n == 0 {
+                    self.write_subpacket(&[], ZCRCE)?;
+                    self.write_header(Frame::at(ZEOF, pos))?;
+                    break;
+                }
+
+                unacked += n;
+                let end = if unacked >= self.window { ZCRCQ } else { ZCRCG };
+                self.write_subpacket(&buf[..n], end)?;
+                pos += n;
+                self.record_progress(pos - start);
+                if end == ZCRCQ {
+                    unacked = 0;
+                    outstanding += 1;
+                }
+
+                while outstanding > 1 {
+                    match self.read_header() {
+                        Ok(f) if f.kind == ZACK => {
+                            outstanding -= 1;
+                            acked = core::cmp::max(acked, f.position());
+                            failures = 0;
+                        }
+                        Ok(f) if f.kind == ZRPOS => {
+                            pos = self.rewind(f.position(), &mut failures)?;
+                            continue 'restart;
+                        }
+                        Ok(f) => return self.unexpected(f),
+                        Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => {
+                            pos = self.rewind(acked, &mut failures)?;
+                            continue 'restart;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+
+            loop {
+                match self.read_header() {
+                    Ok(f) if f.kind == ZRINIT => return Ok(pos - start),
+                    Ok(f) if f.kind == ZACK => continue,
+                    Ok(f) if f.kind == ZRPOS => {
+                        pos = self.rewind(f.position(), &mut failures)?;
+                        continue 'restart;
+                    }
+                    Ok(f) => return self.unexpected(f),
+                    Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => {
+                        failures += 1;
+                        if failures > MAX_RETRIES {
+                            return ioerr!(BrokenPipe, "no response to ZEOF");
+                        }
+                        self.write_header(Frame::at(ZEOF, pos))?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Ends the session after the last file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or writing to the inner stream fails or the
+    /// receiver doesn't respond.
+    pub fn finish(&mut self) -> io::Result<()> {
+        for _ in 0..=MAX_RETRIES {
+            self.write_hex_header(Frame::new(ZFIN, [0; 4]))?;
+            loop {
+                match self.read_header() {
+                    Ok(f) if f.kind == ZFIN => {
+                        self.started = false;
+                        self.inner.write_all(b"OO")?;
+                        return self.flush();
+                    }
+                    Ok(f) if f.kind == ZABORT || f.kind == ZFERR || f.kind == ZCAN => {
+                        return self.unexpected(f);
+                    }
+                    Ok(_) => continue,
+                    Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        ioerr!(BrokenPipe, "no response to ZFIN")
+    }
+
+    /// Receives (downloads) the next file header of the session. Returns
+    /// `None` if the sender ended the session. Otherwise, the file's contents
+    /// must be read with [`Zmodem::receive_file()`] before the next header.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or writing to the inner stream fails or the
+    /// sender doesn't respond. An error of kind `ConnectionAborted` is
+    /// returned if the sender aborts the session, and one of kind
+    /// `InvalidData` if the file header is malformed.
+    pub fn next_file(&mut self) -> io::Result<Option<Header>> {
+        let mut buf = [0u8; SUBPACKET_LEN];
+        let mut failures = 0;
+        self.write_receiver_init()?;
+        loop {
+            let frame = match self.read_header() {
+                Ok(frame) => frame,
+                Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => {
+                    failures += 1;
+                    if failures > MAX_RETRIES {
+                        return ioerr!(TimedOut, "no file header");
+                    }
+                    self.write_receiver_init()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            match frame.kind {
+                ZFILE => match self.read_subpacket(&mut buf) {
+                    Ok((n, _)) => match Header::decode(&buf[..n])? {
+                        Some(header) => return Ok(Some(header)),
+                        None => return ioerr!(InvalidData, "empty file name"),
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        self.write_hex_header(Frame::new(ZNAK, [0; 4]))?;
+                    }
+                    Err(e) => return Err(e),
+                },
+                ZSINIT => {
+                    // Attention strings aren't supported; acknowledge and move on.
+                    match self.read_subpacket(&mut buf) {
+                        Ok(_) => self.write_hex_header(Frame::new(ZACK, [0; 4]))?,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            self.write_hex_header(Frame::new(ZNAK, [0; 4]))?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                ZFIN => {
+                    // The session is over; the sender may hang up as soon as
+                    // it has seen our ZFIN.
+                    let _ = self.write_hex_header(Frame::new(ZFIN, [0; 4]));
+                    self.read_over_and_out();
+                    return Ok(None);
+                }
+                ZABORT | ZFERR | ZCAN => return self.unexpected(frame),
+                _ => self.write_receiver_init()?,
+            }
+        }
+    }
+
+    /// Receives (downloads) the contents of the file described by `header`
+    /// into `into`. If `header.size` is set, data past the end of the file is
+    /// discarded. Returns the number of bytes written to `into`.
+    ///
+    /// To resume a file after a crash, set `offset` to the number of bytes of
+    /// it already received: the sender skips them, and `into` only receives
+    /// the rest.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or writing to the inner stream fails or the
+    /// sender stops responding. An error of kind `ConnectionAborted` is
+    /// returned if the sender aborts the session. If writing to `into` fails,
+    /// the session is cancelled (see [`Zmodem::cancel()`]) and the error is
+    /// returned.
+    pub fn receive_file<W: io::Write>(&mut self, header: &Header, mut into: W, offset: usize) -> io::Result<usize> {
+        self.reset_stats(header.size.map(|size| size.saturating_sub(offset)));
+        let mut buf = [0u8; SUBPACKET_LEN];
+        let mut pos = offset;
+        let mut failures = 0;
+        self.write_hex_header(Frame::at(ZRPOS, pos))?;
+        loop {
+            let frame = match self.read_header() {
+                Ok(frame) => frame,
+                Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => {
+                    self.retry_at(pos, &mut failures)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            match frame.kind {
+                ZDATA if frame.position() == pos => loop {
+                    match self.read_subpacket(&mut buf) {
+                        Ok((n, end)) => {
+                            let keep = match header.size {
+                                Some(size) => core::cmp::min(n, size.saturating_sub(pos)),
+                                None => n,
+                            };
+                            if let Err(e) = into.write_all(&buf[..keep]) {
+                                let _ = self.cancel();
+                                return Err(e);
+                            }
+
+                            pos += n;
+                            failures = 0;
+                            self.record_progress(pos - offset);
+                            if end == ZCRCQ || end == ZCRCW {
+                                self.write_hex_header(Frame::at(ZACK, pos))?;
+                            }
+                            if end == ZCRCE || end == ZCRCW {
+                                break;
+                            }
+                        }
+                        Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => {
+                            self.retry_at(pos, &mut failures)?;
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                },
+                ZEOF if frame.position() == pos => {
+                    let received = pos - offset;
+                    return Ok(match header.size {
+                        Some(size) => core::cmp::min(received, size.saturating_sub(offset)),
+                        None => received,
+                    });
+                }
+                ZFILE => {
+                    // The sender missed our ZRPOS for this file.
+                    let _ = self.read_subpacket(&mut buf);
+                    self.write_hex_header(Frame::at(ZRPOS, pos))?;
+                }
+                ZDATA | ZEOF => self.retry_at(pos, &mut failures)?,
+                ZABORT | ZFERR | ZCAN | ZFIN => return self.unexpected(frame),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Cancels the session by sending the ZMODEM abort sequence and
+    /// discarding input until the line is quiet. See
+    /// [`Xmodem::cancel()`](crate::Xmodem::cancel()).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to or reading from the inner stream fails.
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.started = false;
+        self.inner.write_all(&[CAN; 8])?;
+        self.inner.write_all(&[BS; 8])?;
+        self.flush()?;
+        drain(&mut self.inner)
+    }
+
+    /// Starts a session as the sender: requests the receiver's capabilities
+    /// and waits for them.
+    fn start_send(&mut self) -> io::Result<()> {
+        (self.progress)(Progress::Waiting);
+        self.write_hex_header(Frame::new(ZRQINIT, [0; 4]))?;
+        for _ in 0..=MAX_RETRIES {
+            match self.read_header() {
+                Ok(f) if f.kind == ZRINIT => {
+                    self.crc32 = f.flags() & CANFC32 != 0;
+                    self.started = true;
+                    (self.progress)(Progress::Started);
+                    return Ok(());
+                }
+                Ok(f) if f.kind == ZABORT || f.kind == ZFERR || f.kind == ZCAN => {
+                    return self.unexpected(f);
+                }
+                Ok(_) => continue,
+                Err(ref e) if is_timeout(e) => self.write_hex_header(Frame::new(ZRQINIT, [0; 4]))?,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        ioerr!(TimedOut, "no ZRINIT from receiver")
+    }
+
+    /// Offers the file described by `header` to the receiver. Returns the
+    /// position the receiver wants the data from, or `None` if it skips the
+    /// file.
+    fn send_file_header(&mut self, header: &Header) -> io::Result<Option<usize>> {
+        let mut info = [0u8; 1024];
+        header.encode(&mut info);
+        let len = info.iter().rposition(|b| *b != 0).map_or(0, |i| i + 2);
+
+        for _ in 0..=MAX_RETRIES {
+            self.write_header(Frame::new(ZFILE, [0, 0, 0, ZCBIN]))?;
+            self.write_subpacket(&info[..len], ZCRCW)?;
+            loop {
+                match self.read_header() {
+                    Ok(f) if f.kind == ZRPOS => return Ok(Some(f.position())),
+                    Ok(f) if f.kind == ZSKIP => return Ok(None),
+                    Ok(f) if f.kind == ZNAK => break,
+                    // A repeated ZRINIT or stale ZACK; the answer is still coming.
+                    Ok(f) if f.kind == ZRINIT || f.kind == ZACK => continue,
+                    Ok(f) => return self.unexpected(f),
+                    Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        ioerr!(BrokenPipe, "no response to ZFILE")
+    }
+
+    /// Counts a failure to make progress and returns `pos`, the position to
+    /// resume sending from.
+    fn rewind(&mut self, pos: usize, failures: &mut usize) -> io::Result<usize> {
+        *failures += 1;
+        if *failures > MAX_RETRIES {
+            return ioerr!(BrokenPipe, "too many errors");
+        }
+
+        self.record_retry();
+        Ok(pos)
+    }
+
+    /// Counts a failure to make progress and asks the sender to resume from
+    /// `pos`.
+    fn retry_at(&mut self, pos: usize, failures: &mut usize) -> io::Result<()> {
+        *failures += 1;
+        if *failures > MAX_RETRIES {
+            return ioerr!(BrokenPipe, "too many errors");
+        }
+
+        self.record_retry();
+        self.write_hex_header(Frame::at(ZRPOS, pos))
+    }
+
+    /// Returns the error for an unexpected header `frame`.
+    fn unexpected<U>(&self, frame: Frame) -> io::Result<U> {
+        match frame.kind {
+            ZABORT | ZFERR | ZCAN => ioerr!(ConnectionAborted, "session aborted"),
+            _ => ioerr!(InvalidData, "unexpected header"),
+        }
+    }
+
+    /// Sends the receiver's capabilities.
+    fn write_receiver_init(&mut self) -> io::Result<()> {
+        self.write_hex_header(Frame::new(ZRINIT, [0, 0, 0, CANFDX | CANOVIO | CANFC32]))
+    }
+
+    /// Reads the sender's final "OO", ignoring errors.
+    fn read_over_and_out(&mut self) {
+        let mut seen = 0;
+        for _ in 0..4 {
+            match self.read_byte() {
+                Ok(b'O') => seen += 1,
+                Ok(_) => {}
+                Err(_) => return,
+            }
+            if seen == 2 {
+                return;
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Reads a byte of a ZDLE-escaped stream, skipping flow control bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the inner stream fails. An error of
+    /// kind `ConnectionAborted` is returned for an abort sequence and one of
+    /// kind `Interrupted` for an invalid escape sequence.
+    fn read_escaped(&mut self) -> io::Result<Escaped> {
+        let mut byte = self.read_byte()?;
+        while byte & 0x7f == XON || byte & 0x7f == XOFF {
+            byte = self.read_byte()?;
+        }
+        if byte != ZDLE {
+            return Ok(Escaped::Byte(byte));
+        }
+
+        let mut byte = self.read_byte()?;
+        while byte & 0x7f == XON || byte & 0x7f == XOFF {
+            byte = self.read_byte()?;
+        }
+        match byte {
+            ZCRCE | ZCRCG | ZCRCQ | ZCRCW => Ok(Escaped::End(byte)),
+            ZRUB0 => Ok(Escaped::Byte(0x7f)),
+            ZRUB1 => Ok(Escaped::Byte(0xff)),
+            CAN => ioerr!(ConnectionAborted, "received CAN"),
+            _ if byte & 0x60 == 0x40 => Ok(Escaped::Byte(byte ^ 0x40)),
+            _ => ioerr!(Interrupted, "invalid escape sequence"),
+        }
+    }
+
+    /// Reads `buf.len()` escaped bytes that may not end a subpacket.
+    fn read_escaped_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        for byte in buf.iter_mut() {
+            *byte = match self.read_escaped()? {
+                Escaped::Byte(b) => b,
+                Escaped::End(_) => return ioerr!(Interrupted, "unexpected end of subpacket"),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Reads two hex digits.
+    fn read_hex(&mut self) -> io::Result<u8> {
+        let mut value = 0;
+        for _ in 0..2 {
+            let digit = match self.read_byte()? & 0x7f {
+                b @ b'0'..=b'9' => b - b'0',
+                b @ b'a'..=b'f' => b - b'a' + 10,
+                b @ b'A'..=b'F' => b - b'A' + 10,
+                _ => return ioerr!(Interrupted, "invalid hex digit"),
+            };
+            value = value << 4 | digit;
+        }
+
+        Ok(value)
+    }
+
+    /// Reads the next header, skipping any garbage before it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the inner stream fails. An error of
+    /// kind `Interrupted` is returned if the header is corrupted and one of
+    /// kind `ConnectionAborted` if the other side aborts the session.
+    fn read_header(&mut self) -> io::Result<Frame> {
+        let mut cans = 0;
+        loop {
+            let mut byte = self.read_byte()?;
+            cans = if byte == CAN { cans + 1 } else { 0 };
+            if cans >= ABORT_CANS {
+                return ioerr!(ConnectionAborted, "received CAN");
+            }
+            if byte & 0x7f != ZPAD {
+                continue;
+            }
+
+            while byte & 0x7f == ZPAD {
+                byte = self.read_byte()?;
+            }
+            if byte != ZDLE {
+                continue;
+            }
+
+            let format = self.read_byte()?;
+            let mut bytes = [0u8; 5];
+            let valid = match format & 0x7f {
+                ZHEX => {
+                    for byte in bytes.iter_mut() {
+                        *byte = self.read_hex()?;
+                    }
+                    let crc = u16::from_be_bytes([self.read_hex()?, self.read_hex()?]);
+                    self.frame_crc32 = false;
+                    crc == update_crc(0, &bytes)
+                }
+                ZBIN => {
+                    self.read_escaped_exact(&mut bytes)?;
+                    let mut crc = [0u8; 2];
+                    self.read_escaped_exact(&mut crc)?;
+                    self.frame_crc32 = false;
+                    u16::from_be_bytes(crc) == update_crc(0, &bytes)
+                }
+                ZBIN32 => {
+                    self.read_escaped_exact(&mut bytes)?;
+                    let mut crc = [0u8; 4];
+                    self.read_escaped_exact(&mut crc)?;
+                    self.frame_crc32 = true;
+                    u32::from_le_bytes(crc) == get_crc32(&bytes)
+                }
+                _ => continue,
+            };
+
+            if !valid {
+                return ioerr!(Interrupted, "invalid header CRC");
+            }
+
+            return Ok(Frame::new(bytes[0], [bytes[1], bytes[2], bytes[3], bytes[4]]));
+        }
+    }
+
+    /// Reads a data subpacket into `buf`. Returns the number of data bytes and
+    /// the subpacket's terminator. The CRC is the one used by the last header.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the inner stream fails. An error of
+    /// kind `Interrupted` is returned if the subpacket is corrupted or longer
+    /// than `buf`, and one of kind `ConnectionAborted` if the other side
+    /// aborts the session.
+    pub(crate) fn read_subpacket(&mut self, buf: &mut [u8]) -> io::Result<(usize, u8)> {
+        let mut n = 0;
+        let end = loop {
+            match self.read_escaped()? {
+                Escaped::Byte(_) if n == buf.len() => return ioerr!(Interrupted, "subpacket too long"),
+                Escaped::Byte(b) => {
+                    buf[n] = b;
+                    n += 1;
+                }
+                Escaped::End(end) => break end,
+            }
+        };
+
+        let valid = if self.frame_crc32 {
+            let mut crc = [0u8; 4];
+            self.read_escaped_exact(&mut crc)?;
+            u32::from_le_bytes(crc) == !update_crc32(update_crc32(!0, &buf[..n]), &[end])
+        } else {
+            let mut crc = [0u8; 2];
+            self.read_escaped_exact(&mut crc)?;
+            u16::from_be_bytes(crc) == update_crc(update_crc(0, &buf[..n]), &[end])
+        };
+
+        if !valid {
+            return ioerr!(Interrupted, "invalid subpacket CRC");
+        }
+
+        Ok((n, end))
+    }
+
+    /// Writes `frame` as a hex header, used for all headers sent by the
+    /// receiver.
+    fn write_hex_header(&mut self, frame: Frame) -> io::Result<()> {
+        let mut out = [0u8; 24];
+        out[..4].copy_from_slice(&[ZPAD, ZPAD, ZDLE, ZHEX]);
+        let bytes = frame.bytes();
+        let mut len = bytes.iter().fold(4, |len, b| hex(*b, &mut out, len));
+        len = update_crc(0, &bytes).to_be_bytes().iter().fold(len, |len, b| hex(*b, &mut out, len));
+        out[len] = b'\r';
+        out[len + 1] = b'\n' | 0x80;
+        len += 2;
+        if frame.kind != ZACK && frame.kind != ZFIN {
+            out[len] = XON;
+            len += 1;
+        }
+
+        self.inner.write_all(&out[..len])?;
+        self.flush()
+    }
+
+    /// Writes `frame` as a binary header with the negotiated CRC.
+    fn write_header(&mut self, frame: Frame) -> io::Result<()> {
+        let mut out = [0u8; 32];
+        let bytes = frame.bytes();
+        out[..3].copy_from_slice(&[ZPAD, ZDLE, if self.crc32 { ZBIN32 } else { ZBIN }]);
+        let mut len = bytes.iter().fold(3, |len, b| escape(*b, &mut out, len));
+        len = if self.crc32 {
+            get_crc32(&bytes).to_le_bytes().iter().fold(len, |len, b| escape(*b, &mut out, len))
+        } else {
+            update_crc(0, &bytes).to_be_bytes().iter().fold(len, |len, b| escape(*b, &mut out, len))
+        };
+
+        self.inner.write_all(&out[..len])?;
+        self.flush()
+    }
+
+    /// Writes `data` as a subpacket ending with `end`.
+    pub(crate) fn write_subpacket(&mut self, data: &[u8], end: u8) -> io::Result<()> {
+        let mut out = [0u8; 2 * SUBPACKET_LEN + 10];
+        let mut len = data.iter().fold(0, |len, b| escape(*b, &mut out, len));
+        out[len] = ZDLE;
+        out[len + 1] = end;
+        len += 2;
+        len = if self.crc32 {
+            let crc = !update_crc32(update_crc32(!0, data), &[end]);
+            crc.to_le_bytes().iter().fold(len, |len, b| escape(*b, &mut out, len))
+        } else {
+            let crc = update_crc(update_crc(0, data), &[end]);
+            crc.to_be_bytes().iter().fold(len, |len, b| escape(*b, &mut out, len))
+        };
+
+        self.inner.write_all(&out[..len])?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}